@@ -0,0 +1,83 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, Context};
+use kabina_db::{File, SharedDatabase};
+
+/// List of files written by the previous build, relative to the output directory
+const MANIFEST: &str = ".kabina-build";
+
+pub fn build_output(
+	db: &SharedDatabase,
+	files: &BTreeMap<PathBuf, File>,
+	out: &Path,
+) -> Result<(), anyhow::Error> {
+	std::fs::create_dir_all(out)?;
+
+	let manifest = out.join(MANIFEST);
+	let previous = match std::fs::read_to_string(&manifest) {
+		Ok(content) => content
+			.lines()
+			.map(PathBuf::from)
+			.collect::<BTreeSet<_>>(),
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeSet::new(),
+		Err(e) => return Err(e.into()),
+	};
+
+	for (path, file) in files {
+		if !is_contained(path) {
+			bail!("Collection path {:?} points outside of the output directory", path);
+		}
+
+		let source = file.path(&*db.lock());
+		let target = out.join(path);
+
+		if let Some(parent) = target.parent() {
+			std::fs::create_dir_all(parent)?;
+		}
+
+		tracing::info!("Writing {:?}", target);
+		std::fs::copy(&source, &target)
+			.with_context(|| format!("Failed to copy {:?} to {:?}", source, target))?;
+	}
+
+	for stale in previous.iter().filter(|p| !files.contains_key(*p)) {
+		if !is_contained(stale) {
+			continue;
+		}
+
+		let target = out.join(stale);
+		tracing::info!("Removing stale {:?}", target);
+
+		match std::fs::remove_file(&target) {
+			Ok(_) => remove_empty_parents(out, &target),
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+			Err(e) => return Err(e.into()),
+		}
+	}
+
+	let content = files
+		.keys()
+		.map(|p| p.to_string_lossy())
+		.collect::<Vec<_>>()
+		.join("\n");
+
+	std::fs::write(manifest, content)?;
+
+	Ok(())
+}
+
+fn is_contained(path: &Path) -> bool {
+	path.components()
+		.all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+fn remove_empty_parents(out: &Path, path: &Path) {
+	let mut dir = path.parent();
+	while let Some(d) = dir {
+		if d == out || std::fs::remove_dir(d).is_err() {
+			break;
+		}
+		dir = d.parent();
+	}
+}
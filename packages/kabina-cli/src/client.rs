@@ -1,12 +1,122 @@
-use kabina_rpc::KabinaObserver;
+use std::collections::BTreeMap;
+use std::io::IsTerminal;
+use std::sync::Arc;
+
+use kabina_rpc::{Event, KabinaObserver, LogLine, LogStream, TaskKind};
+use parking_lot::Mutex;
 use tarpc::context::Context;
 
-#[derive(Clone)]
-pub struct KabinaObserverImpl {}
+/// Longest task name shown on the progress line, so it does not wrap
+const PROGRESS_NAME_WIDTH: usize = 60;
+
+#[derive(Clone, Default)]
+pub struct KabinaObserverImpl {
+	progress: Arc<Mutex<Progress>>,
+}
 
 #[tarpc::server]
 impl KabinaObserver for KabinaObserverImpl {
 	async fn log(self, _: Context, name: String) {
 		tracing::info!("Hello, {name}! You are connected")
 	}
+
+	async fn service_log(self, _: Context, line: LogLine) {
+		let mut progress = self.progress.lock();
+		progress.clear();
+
+		match line.stream {
+			LogStream::Stdout => println!("[{}] {}", line.service, line.line),
+			LogStream::Stderr => eprintln!("[{}] {}", line.service, line.line),
+		}
+
+		progress.draw();
+	}
+
+	async fn event(self, _: Context, event: Event) {
+		self.progress.lock().event(event);
+	}
+}
+
+/// Progress of the daemon tasks, drawn as a single line on terminals
+#[derive(Default)]
+struct Progress {
+	running: BTreeMap<u64, String>,
+	finished: usize,
+	files: usize,
+	cached: usize,
+	drawn: bool,
+}
+
+impl Progress {
+	fn event(&mut self, event: Event) {
+		match event {
+			Event::TaskStarted { id, kind, name } => {
+				self.running.insert(id, format!("{} {}", task_label(kind), name));
+			}
+			Event::TaskFinished { id, .. } => {
+				self.running.remove(&id);
+				self.finished += 1;
+			}
+			Event::TaskFailed {
+				id,
+				duration,
+				error,
+			} => {
+				let task = self.running.remove(&id).unwrap_or_default();
+				self.finished += 1;
+				self.print(format!("Failed {} after {}ms: {}", task, duration, error));
+			}
+			Event::FilesWalked { files, .. } => self.files += files,
+			Event::CacheHit { .. } => self.cached += 1,
+			Event::ServiceState { service, state } => {
+				self.print(format!("[{}] {:?}", service, state))
+			}
+			Event::Diagnostic(diagnostic) => self.print(diagnostic.to_string()),
+		}
+
+		self.draw();
+	}
+
+	/// Prints the line above the progress line
+	fn print(&mut self, line: String) {
+		self.clear();
+		eprintln!("{}", line);
+	}
+
+	fn clear(&mut self) {
+		if self.drawn {
+			eprint!("\r\x1b[2K");
+			self.drawn = false;
+		}
+	}
+
+	fn draw(&mut self) {
+		self.clear();
+
+		let Some(current) = self.running.values().next() else {
+			return;
+		};
+
+		if !std::io::stderr().is_terminal() {
+			return;
+		}
+
+		eprint!(
+			"{} running, {} done, {} files, {} cached: {}",
+			self.running.len(),
+			self.finished,
+			self.files,
+			self.cached,
+			current.chars().take(PROGRESS_NAME_WIDTH).collect::<String>(),
+		);
+		self.drawn = true;
+	}
+}
+
+fn task_label(kind: TaskKind) -> &'static str {
+	match kind {
+		TaskKind::WalkFiles => "walking",
+		TaskKind::Transform => "transforming",
+		TaskKind::ResolveBinary => "resolving",
+	}
 }
@@ -1,9 +1,12 @@
-use std::fs::File;
+use std::fs::{DirBuilder, File, Permissions};
+use std::net::SocketAddr;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
 use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use futures::{future, StreamExt};
+use anyhow::{bail, Context};
+use futures::{future, stream, StreamExt};
 use kabina_db::Database;
 use kabina_rpc::{Kabina, KabinaClient, KabinaObserver, KabinaObserverClient};
 use parking_lot::Mutex;
@@ -14,10 +17,14 @@ use tokio::runtime::Runtime;
 use tokio::time::sleep;
 
 use crate::client::KabinaObserverImpl;
+use crate::events::{events_forward, Subscriptions};
+use crate::http::HttpManager;
+use crate::ports::PortAllocator;
 use crate::process::ProcessMananger;
 use crate::rpc::spawn_twoway;
 use crate::runtime::RuntimeManager;
 use crate::server::{KabinaServer, KabinaState, VERSION};
+use crate::watch::WatchManager;
 
 pub fn tokio_current() -> Runtime {
 	tokio::runtime::Builder::new_current_thread()
@@ -33,43 +40,47 @@ pub fn tokio_multi() -> Runtime {
 		.unwrap()
 }
 
+/// Port of the optional TCP listener, which is bound to localhost
+const TCP_PORT_VAR: &str = "KABINA_TCP_PORT";
+
+/// Time the daemon has to start accepting connections, or to exit when it is terminated
+const DAEMON_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest delay between attempts to reach the daemon
+const DAEMON_POLL_MAX: Duration = Duration::from_millis(500);
+
+/// Starts the daemon unless one of the same version is running
 pub fn daemon_start() -> Result<(), anyhow::Error> {
-	let stdout = File::create("/tmp/kabina.out")?;
-	let stderr = File::create("/tmp/kabina.err")?;
+	// The runtime is dropped before forking, its threads would not survive in the child
+	let running = tokio_current().block_on(async {
+		match daemon_client().await {
+			Ok(client) => client.version(current()).await.ok(),
+			Err(_) => None,
+		}
+	});
+
+	match running {
+		Some(version) if version == VERSION.to_string() => return Ok(()),
+		Some(version) => {
+			tracing::info!("Daemon version {} does not match, restarting...", version);
+			daemon_stop()?;
+		}
+		None => {}
+	}
+
+	let runtime_dir = daemon_runtime_dir()?;
+	let stdout = File::create(runtime_dir.join("kabina.out"))?;
+	let stderr = File::create(runtime_dir.join("kabina.err"))?;
 
 	let daemon = daemonize::Daemonize::new()
-		.pid_file(PathBuf::from("/tmp/kabina.pid"))
+		.pid_file(daemon_pid_path()?)
 		.stdout(stdout)
 		.stderr(stderr);
 
 	match daemon.execute() {
 		daemonize::Outcome::Parent(r) => match r {
-			Ok(_p) => {
-				let rt = tokio_current();
-				let restart = rt.block_on(async move {
-					// FIXME: wait until socket is created
-					sleep(Duration::from_millis(300)).await;
-
-					let client = daemon_client().await.unwrap();
-					let version = client.version(current()).await.unwrap();
-
-					if version != VERSION.to_string() {
-						tracing::info!("Daemon version does not match, restarting...");
-						let _ = client.terminate(current()).await;
-						return true;
-					} else {
-						return false;
-					}
-				});
-
-				if restart {
-					std::mem::drop(rt);
-					return daemon_start();
-				}
-
-				Ok(())
-			}
-			Err(e) => panic!("Failed to start the daemon {}", e),
+			Ok(_) => tokio_current().block_on(daemon_wait_ready()),
+			Err(e) => bail!("Failed to start the daemon: {}", e),
 		},
 		daemonize::Outcome::Child(r) => match r {
 			Ok(_) => {
@@ -77,68 +88,159 @@ pub fn daemon_start() -> Result<(), anyhow::Error> {
 				let rt = tokio_multi();
 				rt.block_on(daemon_server())
 			}
-			Err(e) => panic!("Failed to start the daemon: {}", e),
+			Err(e) => bail!("Failed to start the daemon: {}", e),
 		},
 	}
 }
 
+/// Waits until the started daemon answers, the socket is only bound once it is ready to serve
+async fn daemon_wait_ready() -> Result<(), anyhow::Error> {
+	let deadline = Instant::now() + DAEMON_TIMEOUT;
+	let mut delay = Duration::from_millis(10);
+
+	loop {
+		if let Ok(client) = daemon_client().await {
+			let version = client.version(current()).await?;
+			if version != VERSION.to_string() {
+				bail!("Another daemon of version {} was started at the same time", version);
+			}
+
+			return Ok(());
+		}
+
+		if Instant::now() >= deadline {
+			let log = daemon_runtime_dir()?.join("kabina.err");
+			let output = std::fs::read_to_string(&log).unwrap_or_default();
+			let tail = output.lines().rev().take(10).collect::<Vec<_>>();
+			let tail = tail.into_iter().rev().collect::<Vec<_>>().join("\n");
+
+			bail!(
+				"The daemon did not start in {}s, its output is in {:?}:\n{}",
+				DAEMON_TIMEOUT.as_secs(),
+				log,
+				tail
+			);
+		}
+
+		sleep(delay).await;
+		delay = (delay * 2).min(DAEMON_POLL_MAX);
+	}
+}
+
 pub fn daemon_restart() -> Result<(), anyhow::Error> {
 	daemon_stop()?;
 	daemon_start()
 }
 
+/// Terminates the running daemon and waits until its process exits
 pub fn daemon_stop() -> Result<(), anyhow::Error> {
-	let rt = tokio_current();
-	rt.block_on(async {
-		match daemon_client().await {
-			Ok(client) => match client.terminate(current()).await {
-				Ok(_) => unreachable!(),
-				Err(_) => return Ok(()),
-			},
-			Err(_) => return Ok(()),
+	let pid = daemon_pid();
+
+	tokio_current().block_on(async {
+		if let Ok(client) = daemon_client().await {
+			// The daemon exits without answering
+			let _ = client.terminate(current()).await;
 		}
+
+		let Some(pid) = pid else {
+			return Ok(());
+		};
+
+		let deadline = Instant::now() + DAEMON_TIMEOUT;
+		let mut delay = Duration::from_millis(10);
+
+		while process_alive(pid) {
+			if Instant::now() >= deadline {
+				bail!("The daemon (pid {}) did not exit in {}s", pid, DAEMON_TIMEOUT.as_secs());
+			}
+
+			sleep(delay).await;
+			delay = (delay * 2).min(DAEMON_POLL_MAX);
+		}
+
+		Ok(())
 	})
 }
 
-pub async fn daemon_server() -> Result<(), anyhow::Error> {
-	let _ = std::fs::remove_file("/tmp/kabina.sock");
+/// Process id of the running daemon according to its pid file
+fn daemon_pid() -> Option<i32> {
+	let content = std::fs::read_to_string(daemon_pid_path().ok()?).ok()?;
+	let pid = content.trim().parse().ok()?;
+	process_alive(pid).then_some(pid)
+}
 
-	let mut listener =
-		// tarpc::serde_transport::unix::listen("/tmp/kabina.sock", &Bincode::default).await?;
-		tarpc::serde_transport::tcp::listen("0.0.0.0:34123", &Bincode::default).await?;
+fn process_alive(pid: i32) -> bool {
+	// Signal 0 only checks that the process exists
+	unsafe { libc::kill(pid, 0) == 0 }
+}
 
-	let db = Arc::new(Mutex::new(Database::new()));
+pub async fn daemon_server() -> Result<(), anyhow::Error> {
+	let db = Database::open(&daemon_database_path())?.with_store(cache_dir());
+	let db = Arc::new(Mutex::new(db));
 	let rtm = Arc::new(Mutex::new(RuntimeManager::default()));
 	let proc = Arc::new(Mutex::new(ProcessMananger::default()));
+	let watcher = Arc::new(Mutex::new(WatchManager::new(db.clone())?));
 
 	let state = KabinaState {
 		database: db.clone(),
 		process: proc,
+		watcher,
+		http: Arc::new(Mutex::new(HttpManager::default())),
+		ports: Arc::new(Mutex::new(PortAllocator::default())),
+		runs: Default::default(),
 		rtm,
 	};
 
-	listener.config_mut().max_frame_length(usize::MAX);
+	daemon_restore(&state);
 
-	// let (sever, client) = spawn_twoway(listener);
+	let socket = daemon_socket_path()?;
+	let _ = std::fs::remove_file(&socket);
 
-	listener
+	// Only the owner can connect, other users could run arbitrary commands through the daemon.
+	// The socket is bound inside the private runtime directory, so it is never reachable by
+	// others, not even before its own permissions are restricted.
+	let mut unix = tarpc::serde_transport::unix::listen(&socket, Bincode::default).await?;
+	unix.config_mut().max_frame_length(usize::MAX);
+	std::fs::set_permissions(&socket, Permissions::from_mode(0o600))?;
+	tracing::info!("Listening on {:?}", socket);
+
+	let tcp = match std::env::var(TCP_PORT_VAR) {
+		Ok(port) => {
+			let port = port.parse::<u16>().context("Invalid daemon TCP port")?;
+			let addr = SocketAddr::from(([127, 0, 0, 1], port));
+			let mut tcp = tarpc::serde_transport::tcp::listen(addr, Bincode::default).await?;
+			tcp.config_mut().max_frame_length(usize::MAX);
+			tracing::info!("Listening on {}", tcp.local_addr());
+
+			tcp.filter_map(|r| future::ready(r.ok()))
+				.map(spawn_twoway)
+				.boxed()
+		}
+		Err(_) => stream::empty().boxed(),
+	};
+
+	let unix = unix
 		// Ignore accept errors.
 		.filter_map(|r| future::ready(r.ok()))
-		.map(|s| {
-			let (server, client) = spawn_twoway(s);
+		.map(spawn_twoway)
+		.boxed();
+
+	stream::select(unix, tcp)
+		.map(|(server, client)| {
 			let server = tarpc::server::BaseChannel::with_defaults(server);
 			let client =
 				KabinaObserverClient::new(tarpc::client::Config::default(), client).spawn();
-			(server, client)
+			let subscriptions = Subscriptions::default();
+			tokio::spawn(events_forward(client.clone(), subscriptions.clone()));
+			(server, client, subscriptions)
 		})
-		// Limit channels to 1 per IP.
-		// .max_channels_per_key(1, |t| t.transport().peer_addr().unwrap().as_pathname())
 		// serve is generated by the service attribute. It takes as input any type implementing
 		// the generated World trait.
-		.map(|(server_channel, peer)| {
+		.map(|(server_channel, peer, subscriptions)| {
 			let server = KabinaServer {
 				peer,
 				state: state.clone(),
+				subscriptions,
 			};
 			tracing::info!("New connection");
 			server_channel.execute(server.serve())
@@ -151,9 +253,93 @@ pub async fn daemon_server() -> Result<(), anyhow::Error> {
 	Ok(())
 }
 
+/// Per-user directory for the socket, pid and output files, removed with the session
+pub fn daemon_runtime_dir() -> Result<PathBuf, anyhow::Error> {
+	let dir = match std::env::var_os("XDG_RUNTIME_DIR") {
+		Some(runtime) => PathBuf::from(runtime).join("kabina"),
+		None => std::env::temp_dir().join(format!("kabina-{}", unsafe { libc::getuid() })),
+	};
+
+	DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+
+	// The directory may have been created by another user of a shared temporary directory
+	let metadata = std::fs::symlink_metadata(&dir)
+		.with_context(|| format!("Failed to read runtime directory {:?}", dir))?;
+	let uid = unsafe { libc::getuid() };
+	if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o777 != 0o700 {
+		bail!("Runtime directory {:?} must be a directory private to user {}", dir, uid);
+	}
+
+	Ok(dir)
+}
+
+/// Location of the pid file, locked while the daemon is running
+pub fn daemon_pid_path() -> Result<PathBuf, anyhow::Error> {
+	Ok(daemon_runtime_dir()?.join("kabina.pid"))
+}
+
+/// Location of the socket the daemon listens on
+pub fn daemon_socket_path() -> Result<PathBuf, anyhow::Error> {
+	Ok(daemon_runtime_dir()?.join("kabina.sock"))
+}
+
+/// Directory for the daemon data which survives restarts
+pub fn daemon_state_dir() -> PathBuf {
+	let state = std::env::var_os("XDG_STATE_HOME")
+		.map(PathBuf::from)
+		.or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".local/state")))
+		.unwrap_or_else(std::env::temp_dir);
+
+	state.join("kabina")
+}
+
+/// Location of the daemon database, which lists the schemas to load after a restart.
+/// File hashes and transform outputs are kept in the database of each project.
+pub fn daemon_database_path() -> PathBuf {
+	daemon_state_dir().join("kabina.db")
+}
+
+/// Location of the content-addressed store, which can be removed at any time
+pub fn cache_dir() -> PathBuf {
+	let cache = std::env::var_os("XDG_CACHE_HOME")
+		.map(PathBuf::from)
+		.or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".cache")))
+		.unwrap_or_else(std::env::temp_dir);
+
+	cache.join("kabina")
+}
+
+/// Loads schemas registered before the daemon was restarted
+fn daemon_restore(state: &KabinaState) {
+	let stored = match state.database.lock().schema_stored() {
+		Ok(stored) => stored,
+		Err(e) => {
+			tracing::warn!("Failed to read registered schemas: {}", e);
+			return;
+		}
+	};
+
+	for url in stored {
+		if !url.to_file_path().map_or(false, |p| p.exists()) {
+			tracing::info!("Forgetting removed schema {}", url);
+			let _ = state.database.lock().schema_forget(&url);
+			continue;
+		}
+
+		tracing::info!("Restoring schema {}", url);
+
+		let state = state.clone();
+		tokio::spawn(async move {
+			if let Err(e) = state.schema_load(url.clone()).await {
+				tracing::warn!("Failed to restore schema {}: {:#}", url, e);
+			}
+		});
+	}
+}
+
 pub async fn daemon_client() -> Result<KabinaClient, anyhow::Error> {
-	// let mut transport = tarpc::serde_transport::unix::connect("/tmp/kabina.sock", Bincode::default);
-	let mut transport = tarpc::serde_transport::tcp::connect("0.0.0.0:34123", Bincode::default);
+	let mut transport =
+		tarpc::serde_transport::unix::connect(daemon_socket_path()?, Bincode::default);
 	transport.config_mut().max_frame_length(usize::MAX);
 
 	let (server, client) = spawn_twoway(transport.await?);
@@ -161,7 +347,7 @@ pub async fn daemon_client() -> Result<KabinaClient, anyhow::Error> {
 	tokio::spawn(async move {
 		BaseChannel::with_defaults(server)
 			.requests()
-			.execute(KabinaObserverImpl {}.serve())
+			.execute(KabinaObserverImpl::default().serve())
 			.await;
 
 		tracing::error!("Client-side observer server is terminated");
@@ -0,0 +1,71 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::OnceLock;
+
+use kabina_db::{Db, DiagnosticMessage};
+use kabina_rpc::{Diagnostic, Event, Severity};
+use parking_lot::Mutex;
+use url::Url;
+
+use crate::events::emit;
+
+/// Diagnostics sent to the clients of each schema already, queries report them again on
+/// every drive
+static REPORTED: OnceLock<Mutex<HashMap<Url, HashSet<Diagnostic>>>> = OnceLock::new();
+
+fn reported() -> &'static Mutex<HashMap<Url, HashSet<Diagnostic>>> {
+	REPORTED.get_or_init(Default::default)
+}
+
+pub fn diagnostic(db: &dyn Db, d: &DiagnosticMessage) -> Diagnostic {
+	Diagnostic {
+		severity: match d.severity {
+			kabina_db::DiagnosticSeverity::Warning => Severity::Warning,
+			kabina_db::DiagnosticSeverity::Error => Severity::Error,
+		},
+		message: d.message.clone(),
+		source: Some(d.source.describe(db)),
+		location: d.span.as_ref().map(ToString::to_string),
+	}
+}
+
+/// Converts the accumulated diagnostics, dropping the ones reported by several queries
+pub fn diagnostics(db: &dyn Db, messages: &[DiagnosticMessage]) -> Vec<Diagnostic> {
+	let mut diagnostics: Vec<Diagnostic> = Vec::new();
+	for d in messages {
+		let d = diagnostic(db, d);
+		if !diagnostics.contains(&d) {
+			diagnostics.push(d);
+		}
+	}
+
+	diagnostics
+}
+
+/// Sends the diagnostics the clients of the schema have not seen yet
+pub fn diagnostics_emit(db: &dyn Db, schema: &Url, messages: Vec<DiagnosticMessage>) {
+	let mut reported = reported().lock();
+	let reported = reported.entry(schema.clone()).or_default();
+	for d in diagnostics(db, &messages) {
+		if reported.insert(d.clone()) {
+			emit(schema, Event::Diagnostic(d));
+		}
+	}
+}
+
+/// Diagnostics sent to the clients of the schema since it was loaded
+pub fn diagnostics_reported(schema: &Url) -> Vec<Diagnostic> {
+	let reported = reported().lock();
+	let mut diagnostics = reported
+		.get(schema)
+		.map(|r| r.iter().cloned().collect::<Vec<_>>())
+		.unwrap_or_default();
+
+	diagnostics.sort_by_key(|d| (d.source.clone(), d.location.clone(), d.message.clone()));
+	diagnostics
+}
+
+/// Forgets what was reported for the schema, so the diagnostics are sent again once it
+/// is reloaded
+pub fn diagnostics_reset(schema: &Url) {
+	reported().lock().remove(schema);
+}
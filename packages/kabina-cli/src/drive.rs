@@ -1,15 +1,83 @@
+use std::collections::BTreeMap;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
 
+use anyhow::{anyhow, Context};
+use futures::{stream, StreamExt};
 use kabina_db::{
-	BinaryResolve, BinaryRuntime, BinaryRuntimeResolved, Cause, Executable, ResolveRootFiles,
-	RuntimeTask, SharedDatabase, TransformApply,
+	binary_file_revision, interpolate, parse_env_file, BinaryNative, BinaryResolve, BinaryRuntime,
+	BinaryRuntimeResolved, Cause, Database, Db, Diagnostics, Executable, ExecutableKind, Outcome,
+	ResolveRootFiles, RuntimeTask, Schema, SharedDatabase, TransformApply, TransformRestore,
 };
+use kabina_rpc::{Event, QueryError, TaskKind};
+use thiserror::Error;
 use tokio::sync::mpsc::Sender;
+use tokio::sync::{oneshot, watch};
+use url::Url;
 
+use crate::diagnostics::diagnostics_emit;
+use crate::events::{emit, task_id};
 use crate::runtime::RuntimeMessage;
+use crate::status::status_record;
+use crate::watch::watch_executable;
 
-pub macro drive($rt:expr, $func:ident($db:expr, $($arg:expr),+)) {
+/// Stops `drive!` loops between task rounds, tasks that already started are finished
+#[derive(Clone)]
+pub struct Cancellation(Arc<watch::Sender<bool>>);
+
+/// Why `drive!` did not produce a value
+#[derive(Debug, Error)]
+pub enum DriveError {
+	#[error("Cancelled")]
+	Cancelled,
+	#[error(transparent)]
+	Failed(#[from] QueryError),
+}
+
+impl Default for Cancellation {
+	fn default() -> Self {
+		Cancellation(Arc::new(watch::channel(false).0))
+	}
+}
+
+impl Cancellation {
+	pub fn cancel(&self) {
+		self.0.send_replace(true);
+	}
+
+	pub fn is_cancelled(&self) -> bool {
+		*self.0.borrow()
+	}
+
+	/// Completes once the cancellation is requested
+	pub async fn cancelled(&self) {
+		let mut rx = self.0.subscribe();
+		while !*rx.borrow_and_update() {
+			if rx.changed().await.is_err() {
+				return;
+			}
+		}
+	}
+}
+
+pub macro drive {
+	($rt:expr, $func:ident($db:expr, $schema:expr $(, $arg:expr)*)) => {
+		async {
+			match drive!($rt, $func($db, $schema $(, $arg)*), &Cancellation::default()).await {
+				Ok(result) => Ok(result),
+				Err(DriveError::Failed(e)) => Err(e),
+				Err(DriveError::Cancelled) => unreachable!(),
+			}
+		}
+	},
+	($rt:expr, $func:ident($db:expr, $schema:expr $(, $arg:expr)*), $cancel:expr) => {
     async { loop {
+				if $cancel.is_cancelled() {
+					tracing::info!("Cancelled {}", std::stringify!($func));
+					break Err(DriveError::Cancelled);
+				}
+
 				tracing::info!("Resolving {}", std::stringify!($func));
 
         #[allow(unused_assignments)]
@@ -17,71 +85,355 @@ pub macro drive($rt:expr, $func:ident($db:expr, $($arg:expr),+)) {
 
 				{
 					let db_lock = $db.lock();
-					match $func(&*db_lock, $($arg),+) {
+					let outcome = $func(&*db_lock, $schema $(, $arg)*);
+					status_record(&*db_lock, $schema, &($($arg,)*), &outcome);
+					if !matches!(outcome, Err(Cause::Pending)) {
+						let reported =
+							$func::accumulated::<Diagnostics>(&*db_lock, $schema $(, $arg)*);
+						diagnostics_emit(&*db_lock, &$schema.url(&*db_lock), reported);
+					}
+
+					match outcome {
 						Ok(result) => {
-							break result;
+							break Ok(result);
 						}
 						Err(Cause::Pending) => {
 							tracing::info!("Pending {}", std::stringify!($func));
 							tasks = $func::accumulated::<RuntimeTask>(
-									&*db_lock, $($arg),+
+									&*db_lock, $schema $(, $arg)*
 							);
 							tracing::info!("Pending {}", std::stringify!($func));
 						}
-						Err(e) => panic!("{:?}", e),
+						Err(Cause::Error(e)) => {
+							let error = query_error(std::stringify!($func), &e.0);
+							break Err(DriveError::Failed(error));
+						}
 					}
 				}
 
 				tracing::info!("Resolving {}, tasks: {}", std::stringify!($func), tasks.len());
 
-        for task in tasks {
-					// TODO: parallel
-					drive_task(&*task, &$db, &mut $rt).await
-        }
+				if let Err(e) = drive_tasks(tasks, &$db, &$rt).await {
+					break Err(DriveError::Failed(e));
+				}
     } }
+	},
 }
 
-pub async fn drive_task(
-	task: &dyn Executable,
+/// Error of the query for the client, with the whole chain of causes
+pub fn query_error(query: &str, error: &anyhow::Error) -> QueryError {
+	tracing::error!("{} failed: {:#}", query, error);
+
+	QueryError {
+		query: query.to_owned(),
+		causes: error.chain().map(ToString::to_string).collect(),
+	}
+}
+
+/// Upper bound of tasks running at once, `KABINA_JOBS` overrides the number of CPUs
+pub fn jobs_limit() -> usize {
+	std::env::var("KABINA_JOBS")
+		.ok()
+		.and_then(|jobs| jobs.parse().ok())
+		.filter(|jobs| *jobs > 0)
+		.unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()))
+}
+
+/// Stores the result of a task, runs with the database locked
+type TaskApply = Box<dyn FnOnce(&mut Database) -> Outcome<()> + Send>;
+
+struct TaskDone {
+	id: u64,
+	/// Schema of the task, its clients get the events
+	schema: Url,
+	duration: u64,
+	/// Fails when the task can not be executed at all, its query would stay pending
+	apply: Result<TaskApply, anyhow::Error>,
+}
+
+/// Executes the tasks of a round concurrently and stores their results under a single
+/// database lock once all of them are done, fails on a task nobody knows how to execute
+pub async fn drive_tasks(
+	tasks: Vec<Arc<dyn Executable>>,
 	db: &SharedDatabase,
-	_rt: &mut Sender<RuntimeMessage>,
-) {
-	if let Some(task) = task.downcast_ref::<ResolveRootFiles>() {
-		task.resolve(&mut db.lock())
-	} else if let Some(_task) = task.downcast_ref::<TransformApply>() {
-		unimplemented!()
-	} else if let Some(task) = task.downcast_ref::<BinaryResolve>() {
-		let runtime = task.binary.runtime(&*db.lock());
-		let BinaryRuntime::Native(b) = runtime;
-		let url = task.schema.url(&*db.lock());
-		let path = PathBuf::from(url.path());
-		let path = path.parent().unwrap();
-
-		tracing::info!("Resolving binary {} in {}", b.executable, url.path());
-
-		match which::WhichConfig::new()
-			.binary_name(b.executable.clone().into())
-			.custom_cwd(path.to_owned())
-			.first_result()
-		{
-			Ok(path) => {
-				tracing::info!(
-					"[ToolchainResolve] Resolved {:?} to {:?}",
-					b.executable,
-					path
-				);
-				task.resolve(
-					&mut *db.lock(),
-					Ok(BinaryRuntimeResolved::Native {
-						executable: path,
-						args: b.args,
-						env: b.env,
-					}),
-				)
+	rt: &Sender<RuntimeMessage>,
+) -> Result<(), QueryError> {
+	let done = stream::iter(tasks)
+		.map(|task| drive_task(task, db, rt))
+		.buffer_unordered(jobs_limit())
+		.collect::<Vec<_>>()
+		.await;
+
+	let mut db = db.lock();
+	let mut unhandled = None;
+	for task in done {
+		let TaskDone {
+			id,
+			schema,
+			duration,
+			apply,
+		} = task;
+
+		let applied = match apply {
+			Ok(apply) => apply(&mut db),
+			Err(e) => {
+				let cause = Cause::from_anyhow(anyhow!("{:#}", e));
+				unhandled.get_or_insert(e);
+				Err(cause)
 			}
-			Err(e) => task.resolve(&mut *db.lock(), Err(Cause::from_err(e))),
+		};
+
+		let event = match applied {
+			Ok(()) | Err(Cause::Pending) => Event::TaskFinished { id, duration },
+			Err(Cause::Error(e)) => Event::TaskFailed {
+				id,
+				duration,
+				error: format!("{:#}", e.0),
+			},
+		};
+
+		emit(&schema, event);
+	}
+
+	match unhandled {
+		Some(e) => Err(query_error("task_execute", &e)),
+		None => Ok(()),
+	}
+}
+
+/// Executes the task and reports its start to the connected clients
+async fn drive_task(
+	task: Arc<dyn Executable>,
+	db: &SharedDatabase,
+	rt: &Sender<RuntimeMessage>,
+) -> TaskDone {
+	let id = task_id();
+	let (schema, name) = {
+		let db = db.lock();
+		(task.schema().url(&*db), task.name(&*db))
+	};
+
+	let kind = task_kind(task.kind());
+	emit(&schema, Event::TaskStarted { id, kind, name });
+
+	let started = Instant::now();
+	let apply = task_execute(task, &schema, db, rt).await;
+	let duration = started.elapsed().as_millis() as u64;
+
+	TaskDone {
+		id,
+		schema,
+		duration,
+		apply,
+	}
+}
+
+fn task_kind(kind: ExecutableKind) -> TaskKind {
+	match kind {
+		ExecutableKind::WalkFiles => TaskKind::WalkFiles,
+		ExecutableKind::Transform => TaskKind::Transform,
+		ExecutableKind::ResolveBinary => TaskKind::ResolveBinary,
+	}
+}
+
+/// Runs the task without holding the database, returns how to store its result
+async fn task_execute(
+	task: Arc<dyn Executable>,
+	schema: &Url,
+	db: &SharedDatabase,
+	rt: &Sender<RuntimeMessage>,
+) -> Result<TaskApply, anyhow::Error> {
+	let task = match task.downcast_arc::<ResolveRootFiles>() {
+		Ok(task) => {
+			let sqlite = db.lock().sqlite_project(task.schema);
+			let walk = tokio::task::spawn_blocking({
+				let task = task.clone();
+				move || task.walk(&sqlite)
+			});
+			let (files, visited) = match walk.await {
+				Ok(walked) => walked,
+				Err(e) => {
+					let message = format!("Walk of {:?} panicked", task.root);
+					let cause = Cause::from_anyhow(anyhow::Error::new(e).context(message));
+					return Ok(Box::new(move |db| {
+						task.fail(db, cause.clone());
+						Err(cause)
+					}));
+				}
+			};
+
+			let root = task.root.display().to_string();
+			let files_walked = Event::FilesWalked {
+				root,
+				files: visited,
+			};
+			emit(schema, files_walked);
+
+			return Ok(Box::new(move |db| {
+				task.apply(db, files);
+				Ok(())
+			}));
 		}
+		Err(task) => task,
+	};
+
+	if let Ok(task) = task.clone().downcast_arc::<TransformApply>() {
+		// Outputs of previous runs are looked up here, the queries do not track them
+		let lookup = task.lookup(&*db.lock());
+		let found = tokio::task::spawn_blocking(move || lookup.find()).await;
+		if let Ok(Some(restore)) = found {
+			return Ok(transform_restore(restore, schema, db).await);
+		}
+
+		tracing::info!("Applying transform to {:?}", task.file_name);
+
+		let (tx, rx) = oneshot::channel();
+		let message = RuntimeMessage::Transform((*task).clone(), tx);
+		let sent = rt.send(message).await;
+		let result = match sent {
+			Ok(()) => rx.await.unwrap_or_else(|_| Err(runtime_gone(schema))),
+			Err(_) => Err(runtime_gone(schema)),
+		};
+		Ok(Box::new(move |db| {
+			let status = result.as_ref().map(|_| ()).map_err(Clone::clone);
+			task.resolve(db, result);
+			status
+		}))
+	} else if let Ok(task) = task.downcast_arc::<BinaryResolve>() {
+		let name = task.binary.name(&*db.lock());
+		let resolved = binary_resolve_native(db, &task)
+			.map_err(|e| e.context(format!("Binary {:?}", name)));
+		Ok(Box::new(move |db| {
+			let status = resolved.as_ref().map(|_| ()).map_err(Clone::clone);
+			task.resolve(db, resolved);
+			status
+		}))
 	} else {
-		unimplemented!()
+		let (kind, name) = (task.kind(), task.name(&*db.lock()));
+		Err(anyhow!("No executor for {:?} task of {:?}", kind, name))
 	}
 }
+
+/// Puts the output of a previous run in place instead of running the transform
+async fn transform_restore(
+	restore: TransformRestore,
+	schema: &Url,
+	db: &SharedDatabase,
+) -> TaskApply {
+	tracing::info!("Restoring {:?} from the store", restore.target);
+	let path = restore.target.display().to_string();
+	emit(schema, Event::CacheHit { path });
+
+	let source = restore.source(&*db.lock());
+	let restore = Arc::new(restore);
+	let copy = tokio::task::spawn_blocking({
+		let restore = restore.clone();
+		move || restore.copy(source?.as_deref())
+	});
+	let copied = copy
+		.await
+		.unwrap_or_else(|e| Err(std::io::Error::new(std::io::ErrorKind::Other, e)));
+
+	Box::new(move |db| restore.restore(db, copied))
+}
+
+/// Error of a task sent to the runtime of a schema which is no longer running
+fn runtime_gone(schema: &Url) -> Cause {
+	Cause::from_anyhow(anyhow!("Runtime for {} is gone", schema))
+}
+
+fn binary_resolve_native(
+	db: &SharedDatabase,
+	task: &BinaryResolve,
+) -> Outcome<BinaryRuntimeResolved> {
+	let runtime = task.binary.runtime(&*db.lock());
+	let BinaryRuntime::Native(b) = runtime;
+	let url = task.schema.url(&*db.lock());
+	let path = PathBuf::from(url.path());
+	let cwd = b.cwd.clone().unwrap_or_else(|| path.parent().unwrap().to_owned());
+
+	tracing::info!("Resolving binary {} in {:?}", b.executable, cwd);
+
+	let (env, args) = binary_environment(db, task, &b).map_err(Cause::from_anyhow)?;
+
+	let executable = which::WhichConfig::new()
+		.binary_name(b.executable.clone().into())
+		.custom_cwd(cwd.clone())
+		.first_result()
+		.map_err(Cause::from_err)?;
+
+	tracing::info!("[ToolchainResolve] Resolved {:?} to {:?}", b.executable, executable);
+
+	// Outputs of transforms running the binary are stored by its content
+	let revision = binary_file_revision(&executable);
+	let executable_file = db.lock().file_update(executable.clone(), revision);
+	watch_executable(executable.clone());
+
+	Ok(BinaryRuntimeResolved::Native {
+		executable,
+		executable_file,
+		args,
+		env,
+		cwd,
+		inherit_env: b.inherit_env,
+	})
+}
+
+/// Loads the env files and interpolates variable and service port references
+fn binary_environment(
+	db: &SharedDatabase,
+	task: &BinaryResolve,
+	b: &BinaryNative,
+) -> Result<(BTreeMap<String, String>, Vec<String>), anyhow::Error> {
+	let mut env = BTreeMap::new();
+	for file in &b.env_files {
+		let file = file.path(&*db.lock());
+		let content = std::fs::read_to_string(&file)
+			.with_context(|| format!("Failed to read env file {:?}", file))?;
+		env.extend(parse_env_file(&content));
+	}
+
+	let ports = service_ports(db, task.schema);
+	let lookup = |env: &BTreeMap<String, String>, name: &str| {
+		env.get(name)
+			.cloned()
+			.or_else(|| ports.get(name).map(|p| p.to_string()))
+			.or_else(|| b.inherit_env.then(|| std::env::var(name).ok()).flatten())
+	};
+
+	// Values of the binary config see the env files, arguments see the whole environment
+	let mut resolved = env.clone();
+	for (key, value) in &b.env {
+		let value = interpolate(value, |name| lookup(&env, name))
+			.with_context(|| format!("Invalid value of variable {:?}", key))?;
+		resolved.insert(key.clone(), value);
+	}
+
+	let args = b
+		.args
+		.iter()
+		.map(|arg| interpolate(arg, |name| lookup(&resolved, name)))
+		.collect::<Result<Vec<_>, _>>()
+		.context("Invalid binary arguments")?;
+
+	Ok((resolved, args))
+}
+
+/// Ports of the schema services as `name.port` and `name.ports.key` variables
+fn service_ports(db: &SharedDatabase, schema: Schema) -> BTreeMap<String, u16> {
+	let db = db.lock();
+	let mut ports = BTreeMap::new();
+
+	for service in schema.services(&*db).iter() {
+		let service = *service;
+		let name = service.name(&*db);
+		if let Some(port) = service.default_port(&*db) {
+			ports.insert(format!("{}.port", name), port);
+		}
+		for (key, port) in service.fixed_ports(&*db) {
+			ports.insert(format!("{}.ports.{}", name, key), port);
+		}
+	}
+
+	ports
+}
@@ -0,0 +1,65 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+
+use kabina_rpc::{Event, KabinaObserverClient};
+use parking_lot::Mutex;
+use tarpc::context::current;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use url::Url;
+
+/// Events kept for clients that fall behind, older ones are dropped
+const EVENTS_CAPACITY: usize = 1024;
+
+/// Event with the schema it is about
+#[derive(Clone)]
+struct Published {
+	schema: Url,
+	event: Event,
+}
+
+/// Schemas the client of a connection runs, it only receives their events
+pub type Subscriptions = Arc<Mutex<HashSet<Url>>>;
+
+static EVENTS: OnceLock<broadcast::Sender<Published>> = OnceLock::new();
+
+static TASK_ID: AtomicU64 = AtomicU64::new(0);
+
+fn events() -> &'static broadcast::Sender<Published> {
+	EVENTS.get_or_init(|| broadcast::channel(EVENTS_CAPACITY).0)
+}
+
+/// Publishes the event to the clients running the schema, does nothing when nobody listens
+pub fn emit(schema: &Url, event: Event) {
+	let schema = schema.clone();
+	let _ = events().send(Published { schema, event });
+}
+
+pub fn task_id() -> u64 {
+	TASK_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Sends the events of the subscribed schemas to the observer until it disconnects
+pub async fn events_forward(peer: KabinaObserverClient, subscriptions: Subscriptions) {
+	let mut rx = events().subscribe();
+
+	loop {
+		let Published { schema, event } = match rx.recv().await {
+			Ok(published) => published,
+			Err(RecvError::Lagged(skipped)) => {
+				tracing::warn!("Client skipped {} events", skipped);
+				continue;
+			}
+			Err(RecvError::Closed) => break,
+		};
+
+		if !subscriptions.lock().contains(&schema) {
+			continue;
+		}
+
+		if peer.event(current(), event).await.is_err() {
+			break;
+		}
+	}
+}
@@ -0,0 +1,189 @@
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+
+use kabina_db::{Readiness, ReadinessProbe};
+use kabina_rpc::LogLine;
+use regex::Regex;
+use tokio::net::TcpStream;
+use tokio::process::Command;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+
+use crate::logs::ServiceLogs;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Health {
+	Ready,
+	Unhealthy,
+}
+
+/// Probes the service until dropped, the process supervisor listens to the receiver
+pub struct HealthCheck {
+	pub events: UnboundedReceiver<Health>,
+	handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for HealthCheck {
+	fn drop(&mut self) {
+		if let Some(handle) = &self.handle {
+			handle.abort();
+		}
+	}
+}
+
+impl HealthCheck {
+	/// Starts probing, must be called before the process is spawned to see all its output
+	pub fn start(name: &str, readiness: Option<&Readiness>, logs: &Arc<ServiceLogs>) -> Self {
+		let (tx, events) = unbounded_channel();
+
+		// Services without a probe are ready as soon as they are running
+		let Some(readiness) = readiness.cloned() else {
+			let _ = tx.send(Health::Ready);
+			return HealthCheck {
+				events,
+				handle: None,
+			};
+		};
+
+		let name = name.to_owned();
+		let handle = match readiness.probe {
+			ReadinessProbe::Log { pattern } => {
+				let (_, lines) = logs.tail();
+				tokio::spawn(probe_log(name, pattern, lines, tx))
+			}
+			probe => tokio::spawn(probe_periodic(
+				name,
+				probe,
+				Duration::from_millis(readiness.interval),
+				readiness.failure_threshold,
+				tx,
+			)),
+		};
+
+		HealthCheck {
+			events,
+			handle: Some(handle),
+		}
+	}
+}
+
+async fn probe_log(
+	name: String,
+	pattern: String,
+	mut lines: broadcast::Receiver<LogLine>,
+	tx: UnboundedSender<Health>,
+) {
+	let regex = match Regex::new(&pattern) {
+		Ok(regex) => regex,
+		Err(e) => {
+			tracing::error!("Invalid readiness pattern of service {:?}: {}", name, e);
+			return;
+		}
+	};
+
+	loop {
+		match lines.recv().await {
+			Ok(line) if regex.is_match(&line.line) => {
+				let _ = tx.send(Health::Ready);
+				return;
+			}
+			Ok(_) | Err(RecvError::Lagged(_)) => {}
+			Err(RecvError::Closed) => return,
+		}
+	}
+}
+
+async fn probe_periodic(
+	name: String,
+	probe: ReadinessProbe,
+	interval: Duration,
+	failure_threshold: u32,
+	tx: UnboundedSender<Health>,
+) {
+	let mut ready = false;
+	let mut failures = 0;
+
+	loop {
+		sleep(interval).await;
+
+		let success = timeout(interval, check(&probe)).await.unwrap_or(false);
+
+		if success {
+			failures = 0;
+			if !ready {
+				tracing::info!("Service {:?} is ready", name);
+				ready = true;
+				if tx.send(Health::Ready).is_err() {
+					return;
+				}
+			}
+		} else {
+			// Counted while starting too, otherwise a service which never passes its probe
+			// would be starting forever and its dependents would wait for it
+			failures += 1;
+			if failures >= failure_threshold {
+				tracing::warn!("Service {:?} is unhealthy after {} probes", name, failures);
+				ready = false;
+				failures = 0;
+				if tx.send(Health::Unhealthy).is_err() {
+					return;
+				}
+			}
+		}
+	}
+}
+
+async fn check(probe: &ReadinessProbe) -> bool {
+	match probe {
+		ReadinessProbe::Tcp { host, port } => {
+			TcpStream::connect((host.as_str(), *port)).await.is_ok()
+		}
+		ReadinessProbe::Http { url } => {
+			let Ok(uri) = url.parse::<hyper::Uri>() else {
+				return false;
+			};
+
+			match hyper::Client::new().get(uri).await {
+				Ok(response) => response.status().is_success(),
+				Err(_) => false,
+			}
+		}
+		ReadinessProbe::Exec { command, args } => Command::new(command)
+			.args(args)
+			.stdin(Stdio::null())
+			.stdout(Stdio::null())
+			.stderr(Stdio::null())
+			.kill_on_drop(true)
+			.status()
+			.await
+			.map_or(false, |s| s.success()),
+		// Lines are matched as they are written
+		ReadinessProbe::Log { .. } => false,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn test_probe_never_succeeds() {
+		let probe = ReadinessProbe::Exec {
+			command: "false".into(),
+			args: vec![],
+		};
+
+		let (tx, mut events) = unbounded_channel();
+		let interval = Duration::from_millis(10);
+		let handle = tokio::spawn(probe_periodic("test".into(), probe, interval, 3, tx));
+
+		let health = timeout(Duration::from_secs(5), events.recv()).await;
+		handle.abort();
+
+		assert_eq!(health.unwrap(), Some(Health::Unhealthy));
+	}
+}
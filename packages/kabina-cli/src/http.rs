@@ -0,0 +1,400 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_NONE_MATCH};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use kabina_db::{collection_files, Port, Schema, Server, SharedDatabase, SERVER_DEFAULT_PORT};
+use kabina_rpc::{QueryError, ServerInfo};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::{self, Receiver};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+use crate::drive::drive;
+use crate::runtime::RuntimeMessage;
+
+/// Endpoint streaming live-reload events to browsers
+const EVENTS_PATH: &str = "/__kabina/events";
+
+/// Endpoint serving the live-reload client
+const CLIENT_PATH: &str = "/__kabina/reload.js";
+
+const CLIENT: &str = include_str!("reload.js");
+
+/// Interval of comments sent to keep event streams open and detect closed ones
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+struct RunningServer {
+	name: String,
+	addr: SocketAddr,
+	handle: JoinHandle<()>,
+}
+
+/// Serves collections of the schema servers over HTTP
+#[derive(Default)]
+pub struct HttpManager {
+	servers: HashMap<Server, RunningServer>,
+}
+
+#[derive(Clone)]
+struct ServerContext {
+	db: SharedDatabase,
+	rt: Sender<RuntimeMessage>,
+	schema: Schema,
+	server: Server,
+	/// Request paths changed since the previous notification
+	reload: broadcast::Sender<Arc<Vec<String>>>,
+	/// Notified when a browser subscribes to live-reload events
+	connected: Arc<Notify>,
+}
+
+impl HttpManager {
+	/// Binds the server listener, does nothing if the server is already running
+	pub fn start(
+		&mut self,
+		db: SharedDatabase,
+		rt: Sender<RuntimeMessage>,
+		schema: Schema,
+		server: Server,
+		applied: Receiver<()>,
+	) -> Result<SocketAddr, anyhow::Error> {
+		if let Some(running) = self.servers.get(&server) {
+			if !running.handle.is_finished() {
+				return Ok(running.addr);
+			}
+		}
+
+		let (name, port, live_reload) = {
+			let db = db.lock();
+			(server.name(&*db), server.port(&*db), server.live_reload(&*db))
+		};
+
+		// Automatic ports are allocated with the schema, the system picks one if that failed
+		let port = match port {
+			None => SERVER_DEFAULT_PORT,
+			Some(Port::Fixed(port)) => port,
+			Some(Port::Auto) => 0,
+		};
+
+		let addr = SocketAddr::from(([127, 0, 0, 1], port));
+		let cx = ServerContext {
+			db,
+			rt,
+			schema,
+			server,
+			reload: broadcast::channel(16).0,
+			connected: Arc::new(Notify::new()),
+		};
+
+		if live_reload {
+			tokio::spawn(reload_watch(cx.clone(), applied));
+		}
+
+		let make_service = make_service_fn(move |_| {
+			let cx = cx.clone();
+			async move {
+				Ok::<_, Infallible>(service_fn(move |req| {
+					let cx = cx.clone();
+					async move { Ok::<_, Infallible>(serve(cx, req).await) }
+				}))
+			}
+		});
+
+		let http = hyper::Server::try_bind(&addr)?.serve(make_service);
+		let addr = http.local_addr();
+
+		tracing::info!("Server {:?} is listening on http://{}", name, addr);
+
+		let handle = tokio::spawn({
+			let name = name.clone();
+			async move {
+				if let Err(e) = http.await {
+					tracing::error!("Server {:?} failed: {}", name, e);
+				}
+			}
+		});
+
+		self.servers.insert(server, RunningServer { name, addr, handle });
+
+		Ok(addr)
+	}
+
+	pub fn list(&self) -> Vec<ServerInfo> {
+		self.servers
+			.values()
+			.filter(|s| !s.handle.is_finished())
+			.map(|s| ServerInfo {
+				name: s.name.clone(),
+				url: format!("http://{}", s.addr),
+			})
+			.collect()
+	}
+}
+
+async fn serve(cx: ServerContext, req: Request<Body>) -> Response<Body> {
+	if req.method() != Method::GET && req.method() != Method::HEAD {
+		return status(StatusCode::METHOD_NOT_ALLOWED);
+	}
+
+	let path = match percent_decode(req.uri().path()) {
+		Some(path) => path,
+		None => return status(StatusCode::BAD_REQUEST),
+	};
+
+	let (routes, live_reload) = {
+		let db = cx.db.lock();
+		(cx.server.routes(&*db), cx.server.live_reload(&*db))
+	};
+
+	if live_reload && path == EVENTS_PATH {
+		return events(&cx);
+	}
+
+	if live_reload && path == CLIENT_PATH {
+		return Response::builder()
+			.header(CONTENT_TYPE, "application/javascript; charset=utf-8")
+			.body(Body::from(CLIENT))
+			.unwrap();
+	}
+
+	let route = routes
+		.iter()
+		.find_map(|r| r.resolve(&path).map(|p| (r.collection, p)));
+
+	let Some((collection, file_path)) = route else {
+		return status(StatusCode::NOT_FOUND);
+	};
+
+	let ServerContext {
+		db,
+		mut rt,
+		schema,
+		..
+	} = cx;
+
+	// Pending transforms are only applied when somebody asks for the files
+	let files = match drive!(rt, collection_files(db, schema, collection)).await {
+		Ok(files) => files,
+		Err(e) => return query_error(&e),
+	};
+
+	let Some(file) = files.get(&file_path).copied() else {
+		tracing::info!("Not found {:?}", path);
+		return status(StatusCode::NOT_FOUND);
+	};
+
+	let (source, revision) = {
+		let db = db.lock();
+		(file.path(&*db), file.revision(&*db))
+	};
+
+	let etag = format!("\"{:016x}\"", revision);
+	let fresh = req
+		.headers()
+		.get(IF_NONE_MATCH)
+		.and_then(|v| v.to_str().ok())
+		.map_or(false, |v| v.split(',').any(|t| t.trim() == etag));
+
+	let builder = Response::builder()
+		.header(ETAG, &etag)
+		.header(CACHE_CONTROL, "no-cache");
+
+	if fresh {
+		return builder
+			.status(StatusCode::NOT_MODIFIED)
+			.body(Body::empty())
+			.unwrap();
+	}
+
+	let mut content = match tokio::fs::read(&source).await {
+		Ok(content) => content,
+		Err(e) => {
+			tracing::warn!("Failed to read {:?}: {}", source, e);
+			return status(StatusCode::NOT_FOUND);
+		}
+	};
+
+	let content_type = content_type(&file_path);
+	if live_reload && content_type.starts_with("text/html") {
+		content = inject_client(content);
+	}
+
+	let body = match *req.method() {
+		Method::HEAD => Body::empty(),
+		_ => Body::from(content),
+	};
+
+	builder
+		.header(CONTENT_TYPE, content_type)
+		.body(body)
+		.unwrap()
+}
+
+/// Streams paths changed on the server as server-sent events
+fn events(cx: &ServerContext) -> Response<Body> {
+	let mut rx = cx.reload.subscribe();
+	cx.connected.notify_one();
+
+	let (mut sender, body) = Body::channel();
+	tokio::spawn(async move {
+		let mut keep_alive = tokio::time::interval(KEEP_ALIVE);
+		loop {
+			let chunk = tokio::select! {
+				paths = rx.recv() => match paths {
+					Ok(paths) => format!(
+						"event: change\ndata: {}\n\n",
+						serde_json::json!({ "paths": *paths })
+					),
+					Err(RecvError::Lagged(_)) => continue,
+					Err(RecvError::Closed) => break,
+				},
+				_ = keep_alive.tick() => ":\n\n".to_owned(),
+			};
+
+			if sender.send_data(chunk.into()).await.is_err() {
+				break;
+			}
+		}
+	});
+
+	Response::builder()
+		.header(CONTENT_TYPE, "text/event-stream")
+		.header(CACHE_CONTROL, "no-cache")
+		.body(body)
+		.unwrap()
+}
+
+/// Notifies browsers about served paths that changed with the applied file changes
+async fn reload_watch(cx: ServerContext, mut applied: Receiver<()>) {
+	let mut served: Option<BTreeMap<String, u64>> = None;
+
+	loop {
+		tokio::select! {
+			changes = applied.recv() => match changes {
+				Ok(()) | Err(RecvError::Lagged(_)) => {}
+				Err(RecvError::Closed) => break,
+			},
+			_ = cx.connected.notified() => {
+				if served.is_some() {
+					continue;
+				}
+			}
+		}
+
+		// Nobody is listening, so there is no reason to drive the collections
+		if cx.reload.receiver_count() == 0 {
+			served = None;
+			continue;
+		}
+
+		let current = match served_revisions(&cx).await {
+			Ok(current) => current,
+			Err(e) => {
+				tracing::warn!("Not reloading: {}", e);
+				continue;
+			}
+		};
+		if let Some(previous) = &served {
+			let changed = changed_paths(previous, &current);
+			if !changed.is_empty() {
+				tracing::info!("Reloading {} changed paths", changed.len());
+				let _ = cx.reload.send(Arc::new(changed));
+			}
+		}
+
+		served = Some(current);
+	}
+}
+
+/// Revisions of every file served, by request path
+async fn served_revisions(cx: &ServerContext) -> Result<BTreeMap<String, u64>, QueryError> {
+	let routes = cx.server.routes(&*cx.db.lock());
+	let rt = cx.rt.clone();
+	let db = cx.db.clone();
+	let schema = cx.schema;
+
+	let mut served = BTreeMap::new();
+
+	// More specific routes come first and shadow the rest
+	for route in routes.iter().rev() {
+		let collection = route.collection;
+		let files = drive!(rt, collection_files(db, schema, collection)).await?;
+
+		let db_lock = db.lock();
+		for (path, file) in files {
+			served.insert(route.url(&path), file.revision(&*db_lock));
+		}
+	}
+
+	Ok(served)
+}
+
+fn changed_paths(
+	previous: &BTreeMap<String, u64>,
+	current: &BTreeMap<String, u64>,
+) -> Vec<String> {
+	let removed = previous.keys().filter(|p| !current.contains_key(*p));
+	let changed = current
+		.iter()
+		.filter(|(p, r)| previous.get(*p) != Some(*r))
+		.map(|(p, _)| p);
+
+	removed.chain(changed).cloned().collect()
+}
+
+/// Adds the live-reload client to the end of the page body
+fn inject_client(mut html: Vec<u8>) -> Vec<u8> {
+	let script = format!("<script src=\"{}\"></script>", CLIENT_PATH);
+	let position = html
+		.windows(7)
+		.rposition(|w| w.eq_ignore_ascii_case(b"</body>"))
+		.unwrap_or(html.len());
+
+	html.splice(position..position, script.into_bytes());
+	html
+}
+
+fn content_type(path: &Path) -> String {
+	let mime = mime_guess::from_path(path).first_or_octet_stream();
+	match mime.type_() {
+		mime_guess::mime::TEXT => format!("{}; charset=utf-8", mime),
+		_ if mime == mime_guess::mime::APPLICATION_JAVASCRIPT => {
+			format!("{}; charset=utf-8", mime)
+		}
+		_ => mime.to_string(),
+	}
+}
+
+fn percent_decode(path: &str) -> Option<String> {
+	percent_encoding::percent_decode_str(path)
+		.decode_utf8()
+		.ok()
+		.map(|p| p.into_owned())
+}
+
+/// Shows the failed query in the browser, along with the causes
+fn query_error(error: &QueryError) -> Response<Body> {
+	let mut body = format!("{} failed\n", error.query);
+	for cause in &error.causes {
+		body.push_str(&format!("\n{}", cause));
+	}
+
+	Response::builder()
+		.status(StatusCode::INTERNAL_SERVER_ERROR)
+		.header(CONTENT_TYPE, "text/plain; charset=utf-8")
+		.body(Body::from(body))
+		.unwrap()
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+	Response::builder()
+		.status(code)
+		.body(Body::from(code.canonical_reason().unwrap_or_default()))
+		.unwrap()
+}
@@ -0,0 +1,138 @@
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+use kabina_rpc::{LogLine, LogStream};
+use parking_lot::Mutex;
+use tokio::sync::broadcast;
+
+use crate::daemon::daemon_state_dir;
+
+/// Number of recent lines kept in memory for every service
+const BUFFER_LINES: usize = 1000;
+
+/// Size after which the log file is rotated, only one rotated file is kept
+const LOG_FILE_LIMIT: u64 = 10 * 1024 * 1024;
+
+/// Output of a service, kept across its restarts
+pub struct ServiceLogs {
+	service: String,
+	buffer: Mutex<VecDeque<LogLine>>,
+	file: Option<Mutex<RotatingFile>>,
+	lines: broadcast::Sender<LogLine>,
+}
+
+impl ServiceLogs {
+	pub fn new(service: String, file: bool) -> Self {
+		let file = file.then(|| {
+			let path = daemon_state_dir()
+				.join("logs")
+				.join(format!("{}.log", sanitize(&service)));
+
+			Mutex::new(RotatingFile::new(path))
+		});
+
+		ServiceLogs {
+			service,
+			buffer: Mutex::new(VecDeque::with_capacity(BUFFER_LINES)),
+			file,
+			lines: broadcast::channel(BUFFER_LINES).0,
+		}
+	}
+
+	pub fn push(&self, stream: LogStream, line: String) {
+		let line = LogLine {
+			service: self.service.clone(),
+			stream,
+			line,
+		};
+
+		if let Some(file) = &self.file {
+			file.lock().write(&line);
+		}
+
+		{
+			let mut buffer = self.buffer.lock();
+			if buffer.len() == BUFFER_LINES {
+				buffer.pop_front();
+			}
+			buffer.push_back(line.clone());
+		}
+
+		let _ = self.lines.send(line);
+	}
+
+	/// Returns the buffered lines and a receiver of the lines pushed after them
+	pub fn tail(&self) -> (Vec<LogLine>, broadcast::Receiver<LogLine>) {
+		let buffer = self.buffer.lock();
+		(buffer.iter().cloned().collect(), self.lines.subscribe())
+	}
+}
+
+struct RotatingFile {
+	path: PathBuf,
+	file: Option<File>,
+	size: u64,
+}
+
+impl RotatingFile {
+	fn new(path: PathBuf) -> Self {
+		RotatingFile {
+			path,
+			file: None,
+			size: 0,
+		}
+	}
+
+	fn write(&mut self, line: &LogLine) {
+		if let Err(e) = self.try_write(line) {
+			tracing::warn!("Failed to write log file {:?}: {}", self.path, e);
+			self.file = None;
+		}
+	}
+
+	fn try_write(&mut self, line: &LogLine) -> std::io::Result<()> {
+		if self.size >= LOG_FILE_LIMIT {
+			self.file = None;
+			std::fs::rename(&self.path, self.path.with_extension("log.1"))?;
+		}
+
+		let file = match &mut self.file {
+			Some(file) => file,
+			None => {
+				if let Some(parent) = self.path.parent() {
+					std::fs::create_dir_all(parent)?;
+				}
+
+				let file = OpenOptions::new()
+					.create(true)
+					.append(true)
+					.open(&self.path)?;
+
+				self.size = file.metadata()?.len();
+				self.file.insert(file)
+			}
+		};
+
+		let prefix = match line.stream {
+			LogStream::Stdout => "out",
+			LogStream::Stderr => "err",
+		};
+
+		let entry = format!("{} {}\n", prefix, line.line);
+		file.write_all(entry.as_bytes())?;
+		self.size += entry.len() as u64;
+
+		Ok(())
+	}
+}
+
+fn sanitize(name: &str) -> String {
+	name.chars()
+		.map(|c| match c {
+			'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+			_ => '_',
+		})
+		.collect()
+}
@@ -1,18 +1,41 @@
 #![feature(decl_macro)]
 
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use anyhow::{anyhow, bail};
 use clap::Parser;
-use daemon::{daemon_client, daemon_start, tokio_current};
+use daemon::{cache_dir, daemon_client, daemon_start, tokio_current, tokio_multi};
+use diagnostics::diagnostics;
+use drive::drive;
+use kabina_db::{collection_files, Diagnostics};
+use kabina_rpc::{QueryError, RunError};
+use parking_lot::Mutex;
+use runtime::{RuntimeManager, RuntimeMessage};
 use tarpc::context::current;
+use tokio::sync::oneshot;
+use url::Url;
 
+mod build;
 mod client;
 mod daemon;
+mod diagnostics;
 mod drive;
+mod events;
+mod health;
+mod http;
+mod logs;
+mod ports;
 mod process;
 mod rpc;
 mod runtime;
 mod server;
+mod status;
+mod watch;
+
+/// Deadline of runs without a timeout, services keep running after the run
+const RUN_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
 
 /// Simple program to greet a person
 #[derive(Parser, Debug)]
@@ -23,13 +46,60 @@ enum Command {
 		schema: PathBuf,
 		#[arg(long)]
 		collection: String,
+		/// Directory the collection files are written to
+		#[arg(long)]
+		out: PathBuf,
 	},
 	Run {
 		#[arg(index = 1)]
 		schema: String,
+		/// Cancel the run if it takes longer than this many seconds
+		#[arg(long)]
+		timeout: Option<u64>,
+		/// Stop the services started by the run when it is interrupted
+		#[arg(long)]
+		stop_services: bool,
 	},
 	#[clap(subcommand)]
 	Daemon(Daemon),
+	#[clap(subcommand)]
+	Service(Service),
+	#[clap(subcommand)]
+	Server(Server),
+	/// Prints loaded schemas, running processes and servers of the daemon
+	Status {
+		/// Print the status as JSON
+		#[arg(long)]
+		json: bool,
+	},
+	/// Prints the output of a service
+	Logs {
+		#[arg(index = 1)]
+		service: String,
+		/// Keep printing new output until interrupted
+		#[arg(long, short)]
+		follow: bool,
+	},
+}
+
+#[derive(clap::Parser, Debug)]
+enum Service {
+	/// Lists services started by the daemon
+	List {},
+	Stop {
+		#[arg(index = 1)]
+		name: String,
+	},
+	Restart {
+		#[arg(index = 1)]
+		name: String,
+	},
+}
+
+#[derive(clap::Parser, Debug)]
+enum Server {
+	/// Lists servers of the loaded schemas with their addresses
+	List {},
 }
 
 #[derive(clap::Parser, Debug)]
@@ -48,44 +118,78 @@ fn main() -> Result<(), anyhow::Error> {
 		Command::Build {
 			mut schema,
 			collection: bundle,
+			out,
 		} => {
-			// let rt = tokio::runtime::Builder::new_multi_thread()
-			// 	.enable_all()
-			// 	.build()?;
+			let rt = tokio_multi();
+
+			if schema.is_relative() {
+				schema = std::env::current_dir()?.join(schema)
+			}
+
+			let file_url = Url::from_file_path(schema).unwrap();
 
-			// if schema.is_relative() {
-			// 	schema = std::env::current_dir()?.join(schema)
-			// }
+			// Builds share the project database with the daemon
+			let db = kabina_db::Database::new().with_store(cache_dir());
+			db.project_open(&file_url)?;
+			let db = Arc::new(Mutex::new(db));
 
-			// let db = Arc::new(Mutex::new(kabina_db::Database::new()));
+			let mut rtm = RuntimeManager::default();
+			let channel = rtm.spawn(db.clone(), file_url);
+
+			rt.block_on(async {
+				// Populating the schema from TS
+				let schema = {
+					let (tx, rx) = oneshot::channel();
+					channel
+						.send(RuntimeMessage::Schema(tx))
+						.await
+						.map_err(|_| anyhow!("Runtime is not available"))?;
+					rx.await??
+				};
 
-			// let mut deno_rt = Box::new(rt.block_on(DenoRuntime::new(db.clone())));
+				let collection = {
+					let db = db.lock();
+					let collections = schema.collections(&*db);
+					let collection = collections
+						.iter()
+						.find(|b| (*b).name(&*db) == bundle)
+						.map(|b| *b);
 
-			// let file_url = Url::from_file_path(schema).unwrap();
+					collection
+				};
 
-			// // Populating the schema from TS
-			// let schema = rt.block_on(deno_rt.load_schema(file_url));
+				let Some(collection) = collection else {
+					bail!("Collection {:?} is not defined in the schema", bundle)
+				};
 
-			// let collection = {
-			// 	let db = db.lock();
-			// 	let collections = schema.collections(&*db);
-			// 	let collection = collections
-			// 		.iter()
-			// 		.find(|b| (*b).name(&*db) == bundle)
-			// 		.unwrap()
-			// 		.clone();
+				let files = drive!(channel, collection_files(db, schema, collection)).await;
 
-			// 	collection
-			// };
+				// Nobody listens to the events of a build, so diagnostics are printed here
+				{
+					let db = db.lock();
+					let reported =
+						collection_files::accumulated::<Diagnostics>(&*db, schema, collection);
+					for diagnostic in diagnostics(&*db, &reported) {
+						eprintln!("{}", diagnostic);
+					}
+				}
 
-			// rt.block_on(drive::drive!(
-			// 	deno_rt,
-			// 	collection_files(db, schema, collection)
-			// ));
+				let files = match files {
+					Ok(files) => files,
+					Err(e) => {
+						query_errors_print(std::slice::from_ref(&e));
+						bail!("Failed to build collection {:?}", bundle);
+					}
+				};
 
-			Ok(())
+				build::build_output(&db, &files, &out)
+			})
 		}
-		Command::Run { schema } => {
+		Command::Run {
+			schema,
+			timeout,
+			stop_services,
+		} => {
 			daemon_start()?;
 			let rt = tokio_current();
 			rt.block_on(async {
@@ -99,8 +203,31 @@ fn main() -> Result<(), anyhow::Error> {
 					url::Url::from_file_path(path).unwrap()
 				});
 
-				client.schema_run(current(), url).await?;
-				Ok(())
+				let timeout = timeout.map_or(RUN_TIMEOUT, Duration::from_secs);
+				let mut context = current();
+				context.deadline = SystemTime::now() + timeout;
+
+				let run = run_id();
+				let running = client.schema_run(context, url, run);
+				tokio::pin!(running);
+
+				let result = tokio::select! {
+					result = &mut running => result?,
+					_ = tokio::signal::ctrl_c() => {
+						eprintln!("Cancelling...");
+						client.cancel(current(), run, stop_services).await?;
+						match running.await? {
+							Err(RunError::Cancelled) => Ok(()),
+							result => result,
+						}
+					}
+				};
+
+				if let Err(RunError::Failed(errors)) = &result {
+					query_errors_print(errors);
+				}
+
+				Ok(result?)
 			})
 		}
 		Command::Daemon(daemon) => match daemon {
@@ -108,5 +235,96 @@ fn main() -> Result<(), anyhow::Error> {
 			Daemon::Stop {} => daemon::daemon_stop(),
 			Daemon::Restart {} => daemon::daemon_restart(),
 		},
+		Command::Service(service) => {
+			daemon_start()?;
+			let rt = tokio_current();
+			rt.block_on(async {
+				let client = daemon_client().await?;
+				match service {
+					Service::List {} => {
+						for info in client.service_list(current()).await? {
+							let ports = info
+								.ports
+								.iter()
+								.map(|(name, port)| format!("{}={}", name, port))
+								.collect::<Vec<_>>()
+								.join(",");
+
+							println!(
+								"{}\t{}\t{:?}\trestart={} restarts={}\tports={}",
+								info.name,
+								info.pid.map_or("-".to_owned(), |p| p.to_string()),
+								info.state,
+								info.restart,
+								info.restarts,
+								if ports.is_empty() { "-" } else { ports.as_str() },
+							);
+						}
+					}
+					Service::Stop { name } => client.service_stop(current(), name).await??,
+					Service::Restart { name } => client.service_restart(current(), name).await??,
+				}
+
+				Ok(())
+			})
+		}
+		Command::Server(Server::List {}) => {
+			daemon_start()?;
+			let rt = tokio_current();
+			rt.block_on(async {
+				let client = daemon_client().await?;
+				for info in client.server_list(current()).await? {
+					println!("{}\t{}", info.name, info.url);
+				}
+
+				Ok(())
+			})
+		}
+		Command::Status { json } => {
+			daemon_start()?;
+			let rt = tokio_current();
+			rt.block_on(async {
+				let client = daemon_client().await?;
+				let status = client.status(current()).await?;
+
+				if json {
+					println!("{}", serde_json::to_string_pretty(&status)?);
+				} else {
+					status::status_print(&status);
+				}
+
+				Ok(())
+			})
+		}
+		Command::Logs { service, follow } => {
+			daemon_start()?;
+			let rt = tokio_current();
+			rt.block_on(async {
+				let client = daemon_client().await?;
+				client.service_logs(current(), service, follow).await??;
+
+				if follow {
+					tokio::signal::ctrl_c().await?;
+				}
+
+				Ok(())
+			})
+		}
 	}
 }
+
+/// Prints the failed queries with their causes, each cause on its own line
+fn query_errors_print(errors: &[QueryError]) {
+	for error in errors {
+		eprintln!("error: {} failed", error.query);
+		for (depth, cause) in error.causes.iter().enumerate() {
+			eprintln!("{:width$}{}", "", cause, width = 2 * (depth + 1));
+		}
+	}
+}
+
+/// Identifies the run to cancel it, unique enough among the runs of one daemon
+fn run_id() -> u64 {
+	let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+	(now.as_nanos() as u64) ^ ((std::process::id() as u64) << 32)
+}
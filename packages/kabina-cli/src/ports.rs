@@ -0,0 +1,35 @@
+use std::collections::{BTreeMap, HashSet};
+use std::net::TcpListener;
+
+use url::Url;
+
+/// Hands out free ports for `"auto"` service and server ports
+#[derive(Default)]
+pub struct PortAllocator {
+	/// Ports by schema and owner, kept so reloading a schema does not move its ports
+	allocated: BTreeMap<(Url, String), u16>,
+	used: HashSet<u16>,
+}
+
+impl PortAllocator {
+	/// Returns the port allocated for the owner before or a new free one
+	pub fn allocate(&mut self, schema: &Url, owner: String) -> std::io::Result<u16> {
+		let key = (schema.clone(), owner);
+		if let Some(port) = self.allocated.get(&key) {
+			return Ok(*port);
+		}
+
+		// The system picks a free port, it is released right away for the owner to bind it
+		let port = loop {
+			let port = TcpListener::bind(("127.0.0.1", 0))?.local_addr()?.port();
+			if self.used.insert(port) {
+				break port;
+			}
+		};
+
+		tracing::info!("Allocated port {} for {:?}", port, key.1);
+		self.allocated.insert(key, port);
+
+		Ok(port)
+	}
+}
@@ -1,45 +1,596 @@
 use std::collections::BTreeMap;
 use std::path::PathBuf;
+use std::process::{ExitStatus, Stdio};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use tokio::process::Command;
+use anyhow::{bail, Context};
+use kabina_db::{Readiness, RestartPolicy, Service};
+use kabina_rpc::{Event, LogStream, ServiceError, ServiceInfo, ServiceState};
+use parking_lot::Mutex;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::{Child, Command};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time::sleep;
+use url::Url;
+
+use crate::events::emit;
+use crate::health::{Health, HealthCheck};
+use crate::logs::ServiceLogs;
+use crate::runtime::RuntimeMessage;
+
+/// Delay before the first restart, doubled after every consecutive exit
+const BACKOFF_INITIAL: Duration = Duration::from_millis(500);
+const BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+/// Processes running longer than this are considered healthy and restart without a delay
+const BACKOFF_RESET: Duration = Duration::from_secs(10);
 
 #[derive(Default)]
 pub struct ProcessMananger {
 	running: BTreeMap<usize, Arc<Process>>,
+	/// Service ids in the order they were started
+	started: Vec<usize>,
 }
 
 impl ProcessMananger {
-	pub fn spawn(&mut self, id: usize, config: ProcessConfig) -> Arc<Process> {
-		tracing::info!("Spawning a process: {:?}", config.executable);
-		let process = Arc::new(Process::new(config));
-		self.running.insert(id, process.clone());
-		process
+	/// Starts supervising the process, replacing the one previously started for the service.
+	/// The previous process is stopped first, so both never run at once and fight over ports
+	pub async fn spawn(
+		manager: &Mutex<ProcessMananger>,
+		id: usize,
+		name: String,
+		config: ProcessConfig,
+		restart: RestartPolicy,
+		depends_on: Vec<usize>,
+	) -> Arc<Process> {
+		// Another spawn of the service may start it while the previous one is stopping
+		loop {
+			let previous = {
+				let mut manager = manager.lock();
+				manager.started.retain(|s| *s != id);
+
+				let Some(previous) = manager.running.remove(&id) else {
+					tracing::info!("Spawning a process: {:?}", config.executable);
+					let process = Arc::new(Process::new(id, name, config, restart, depends_on));
+					manager.running.insert(id, process.clone());
+					manager.started.push(id);
+					return process;
+				};
+
+				previous
+			};
+
+			previous.stop().await;
+		}
+	}
+
+	pub fn get(&self, id: usize) -> Option<Arc<Process>> {
+		self.running.get(&id).cloned()
+	}
+
+	/// Processes to stop, dependents before their dependencies. Stops everything
+	/// without a `root`, otherwise the root and services depending on it
+	pub fn stop_order(&self, root: Option<usize>) -> Vec<Arc<Process>> {
+		let mut stopping = root.into_iter().collect::<Vec<_>>();
+
+		// Services are started after their dependencies, so one pass finds all dependents
+		for id in self.started.iter() {
+			let depends = self.running[id]
+				.depends_on
+				.iter()
+				.any(|d| stopping.contains(d));
+
+			if depends && !stopping.contains(id) {
+				stopping.push(*id);
+			}
+		}
+
+		self.started
+			.iter()
+			.rev()
+			.filter(|id| root.is_none() || stopping.contains(id))
+			.map(|id| self.running[id].clone())
+			.collect()
+	}
+
+	pub fn find(&self, name: &str) -> Result<Arc<Process>, ServiceError> {
+		let mut found = self.running.values().filter(|p| p.name == name);
+		match (found.next(), found.next()) {
+			(Some(process), None) => Ok(process.clone()),
+			(Some(_), Some(_)) => Err(ServiceError::Ambiguous(name.to_owned())),
+			(None, _) => Err(ServiceError::NotFound(name.to_owned())),
+		}
+	}
+
+	pub fn list(&self) -> Vec<ServiceInfo> {
+		self.running.values().map(|p| p.info()).collect()
 	}
 }
 
 pub struct ProcessConfig {
+	/// Schema declaring the service, its clients are notified about the state changes
+	pub schema: Url,
+	pub executable: PathBuf,
+	pub env: BTreeMap<String, String>,
+	pub args: Vec<String>,
+	pub cwd: PathBuf,
+	/// Clear the daemon environment before `env` is applied when false
+	pub inherit_env: bool,
+	/// Write the output to a rotating log file besides the in-memory buffer
+	pub log_file: bool,
+	pub on_stdout: Option<StdoutCallback>,
+	pub readiness: Option<Readiness>,
+	pub build: Option<BuildConfig>,
+	/// Time between SIGTERM and SIGKILL when the process is stopped
+	pub stop_timeout: Duration,
+	/// Published over RPC, already present in `env`
+	pub ports: BTreeMap<String, u16>,
+}
+
+pub struct BuildConfig {
 	pub executable: PathBuf,
 	pub env: BTreeMap<String, String>,
 	pub args: Vec<String>,
+	pub cwd: PathBuf,
+	pub inherit_env: bool,
+}
+
+/// Passes stdout lines to the schema runtime
+#[derive(Clone)]
+pub struct StdoutCallback {
+	pub runtime: Sender<RuntimeMessage>,
+	pub service: Service,
+}
+
+enum Control {
+	Stop(oneshot::Sender<()>),
+	Restart(oneshot::Sender<()>),
+	/// Runs the build step and restarts the process if it succeeds
+	Rebuild(oneshot::Sender<()>),
+}
+
+struct ProcessStatus {
+	pid: Option<u32>,
+	/// When the running process was spawned
+	spawned: Option<Instant>,
+	restarts: u32,
 }
 
 pub struct Process {
-	pub config: ProcessConfig,
+	pub id: usize,
+	pub name: String,
+	pub config: Arc<ProcessConfig>,
+	pub restart: RestartPolicy,
+	pub logs: Arc<ServiceLogs>,
+	/// Services started before this one
+	pub depends_on: Vec<usize>,
+	status: Arc<Mutex<ProcessStatus>>,
+	state: Arc<watch::Sender<ServiceState>>,
+	control: mpsc::UnboundedSender<Control>,
 }
 
 impl Process {
-	pub fn new(config: ProcessConfig) -> Process {
-		let mut command = Command::new(&config.executable);
-		command.args(config.args.iter());
-		command.envs(config.env.iter());
-		let mut child = command.spawn().unwrap();
-		let _stdin = child.stdin.take();
-		tokio::spawn(async move {
-			let res = child.wait().await;
-			tracing::info!("Process completed with exit code {:?}", res)
-		});
-
-		Process { config }
+	pub fn new(
+		id: usize,
+		name: String,
+		config: ProcessConfig,
+		restart: RestartPolicy,
+		depends_on: Vec<usize>,
+	) -> Process {
+		let logs = Arc::new(ServiceLogs::new(name.clone(), config.log_file));
+		let config = Arc::new(config);
+		let status = Arc::new(Mutex::new(ProcessStatus {
+			pid: None,
+			spawned: None,
+			restarts: 0,
+		}));
+		let state = Arc::new(watch::channel(ServiceState::Starting).0);
+
+		let (control, rx) = mpsc::unbounded_channel();
+
+		tokio::spawn(supervise(
+			name.clone(),
+			config.clone(),
+			restart,
+			logs.clone(),
+			status.clone(),
+			state.clone(),
+			rx,
+		));
+
+		Process {
+			id,
+			name,
+			config,
+			restart,
+			logs,
+			depends_on,
+			status,
+			state,
+			control,
+		}
+	}
+
+	pub fn info(&self) -> ServiceInfo {
+		let status = self.status.lock();
+		ServiceInfo {
+			name: self.name.clone(),
+			pid: status.pid,
+			uptime: status.spawned.map(|s| s.elapsed().as_secs()),
+			state: self.state(),
+			restart: self.restart.as_str().to_owned(),
+			restarts: status.restarts,
+			ports: self.config.ports.clone(),
+		}
+	}
+
+	/// Waits until the readiness probe succeeds, fails if the process exits before that or
+	/// turns unhealthy without a policy to restart it
+	pub async fn wait_ready(&self) -> Result<(), ServiceState> {
+		let mut rx = self.state.subscribe();
+		loop {
+			let state = *rx.borrow_and_update();
+			match state {
+				ServiceState::Ready => return Ok(()),
+				ServiceState::Exited { .. } | ServiceState::Stopped | ServiceState::BuildFailed => {
+					return Err(state)
+				}
+				// Unhealthy services are only restarted by a policy, otherwise they stay so
+				ServiceState::Unhealthy if self.restart == RestartPolicy::Never => {
+					return Err(state)
+				}
+				ServiceState::Building
+				| ServiceState::Starting
+				| ServiceState::Unhealthy
+				| ServiceState::Backoff => {}
+			}
+
+			if rx.changed().await.is_err() {
+				return Err(state);
+			}
+		}
+	}
+
+	/// Kills the process and waits until it exits
+	pub async fn stop(&self) {
+		let (tx, rx) = oneshot::channel();
+		if self.control.send(Control::Stop(tx)).is_ok() {
+			let _ = rx.await;
+		}
+	}
+
+	/// Kills the process and starts it again, even if it has already exited
+	pub async fn restart(&self) {
+		let (tx, rx) = oneshot::channel();
+		if self.control.send(Control::Restart(tx)).is_ok() {
+			let _ = rx.await;
+		}
+	}
+
+	/// Rebuilds the service and restarts it, the process keeps running if the build fails
+	pub async fn rebuild(&self) {
+		let (tx, rx) = oneshot::channel();
+		if self.control.send(Control::Rebuild(tx)).is_ok() {
+			let _ = rx.await;
+		}
+	}
+
+	pub fn state(&self) -> ServiceState {
+		*self.state.borrow()
+	}
+}
+
+/// Runs the process and restarts it according to the policy until it is stopped
+async fn supervise(
+	name: String,
+	config: Arc<ProcessConfig>,
+	restart: RestartPolicy,
+	logs: Arc<ServiceLogs>,
+	status: Arc<Mutex<ProcessStatus>>,
+	state: Arc<watch::Sender<ServiceState>>,
+	mut control: mpsc::UnboundedReceiver<Control>,
+) {
+	let set_state = |s: ServiceState, pid: Option<u32>| {
+		{
+			let mut status = status.lock();
+			if pid.is_none() {
+				status.spawned = None;
+			} else if status.pid.is_none() {
+				status.spawned = Some(Instant::now());
+			}
+			status.pid = pid;
+		}
+
+		if state.send_replace(s) != s {
+			let event = Event::ServiceState {
+				service: name.clone(),
+				state: s,
+			};
+			emit(&config.schema, event);
+		}
+	};
+
+	let mut failures = 0;
+	let mut needs_build = config.build.is_some();
+
+	'supervise: loop {
+		if needs_build {
+			needs_build = false;
+			set_state(ServiceState::Building, None);
+
+			if let Err(e) = run_build(&name, &config, &logs).await {
+				tracing::error!("{}", e);
+				logs.push(LogStream::Stderr, e.to_string());
+				set_state(ServiceState::BuildFailed, None);
+
+				// Waiting for the inputs to change or a manual restart
+				match control.recv().await {
+					Some(Control::Restart(done) | Control::Rebuild(done)) => {
+						needs_build = true;
+						let _ = done.send(());
+						continue;
+					}
+					Some(Control::Stop(done)) => {
+						set_state(ServiceState::Stopped, None);
+						let _ = done.send(());
+						return;
+					}
+					None => return,
+				}
+			}
+		}
+
+		let started = Instant::now();
+		let mut health = HealthCheck::start(&name, config.readiness.as_ref(), &logs);
+
+		let exited = match spawn_child(&config, &logs) {
+			Ok(mut child) => {
+				set_state(ServiceState::Starting, child.id());
+				tracing::info!("Service {:?} is running with pid {:?}", name, child.id());
+
+				loop {
+					tokio::select! {
+						exited = child.wait() => break exited.ok(),
+						Some(event) = health.events.recv() => match event {
+							Health::Ready => set_state(ServiceState::Ready, child.id()),
+							Health::Unhealthy => {
+								set_state(ServiceState::Unhealthy, child.id());
+								if restart != RestartPolicy::Never {
+									stop_child(&name, &mut child, config.stop_timeout).await;
+									break None;
+								}
+							}
+						},
+						command = control.recv() => match command {
+							Some(Control::Rebuild(done)) => {
+								// The running process keeps serving while the new version builds
+								let built = run_build(&name, &config, &logs).await;
+								let _ = done.send(());
+
+								match built {
+									Ok(()) => {
+										stop_child(&name, &mut child, config.stop_timeout).await;
+										failures = 0;
+										continue 'supervise;
+									}
+									Err(e) => {
+										tracing::error!("{}", e);
+										logs.push(LogStream::Stderr, e.to_string());
+									}
+								}
+							}
+							Some(Control::Restart(done)) => {
+								stop_child(&name, &mut child, config.stop_timeout).await;
+								failures = 0;
+								let _ = done.send(());
+								continue 'supervise;
+							}
+							Some(Control::Stop(done)) => {
+								stop_child(&name, &mut child, config.stop_timeout).await;
+								set_state(ServiceState::Stopped, None);
+								let _ = done.send(());
+								return;
+							}
+							None => {
+								stop_child(&name, &mut child, config.stop_timeout).await;
+								set_state(ServiceState::Stopped, None);
+								return;
+							}
+						}
+					}
+				}
+			}
+			Err(e) => {
+				tracing::error!("Failed to spawn service {:?}: {}", name, e);
+				None
+			}
+		};
+
+		let code = exited.and_then(|s| s.code());
+		let success = exited.map_or(false, |s: ExitStatus| s.success());
+		tracing::info!("Service {:?} exited with {:?}", name, exited);
+
+		let restarting = match restart {
+			RestartPolicy::Never => false,
+			RestartPolicy::OnFailure => !success,
+			RestartPolicy::Always => true,
+		};
+
+		if !restarting {
+			set_state(ServiceState::Exited { code }, None);
+
+			// Exited processes can still be restarted manually
+			match control.recv().await {
+				Some(Control::Restart(done)) => {
+					failures = 0;
+					let _ = done.send(());
+					continue;
+				}
+				Some(Control::Rebuild(done)) => {
+					needs_build = config.build.is_some();
+					failures = 0;
+					let _ = done.send(());
+					continue;
+				}
+				Some(Control::Stop(done)) => {
+					set_state(ServiceState::Stopped, None);
+					let _ = done.send(());
+					return;
+				}
+				None => return,
+			}
+		}
+
+		if started.elapsed() > BACKOFF_RESET {
+			failures = 0;
+		}
+
+		let delay = BACKOFF_INITIAL
+			.saturating_mul(2u32.saturating_pow(failures))
+			.min(BACKOFF_MAX);
+		failures += 1;
+
+		set_state(ServiceState::Backoff, None);
+		status.lock().restarts += 1;
+		tracing::info!("Restarting service {:?} in {:?}", name, delay);
+
+		tokio::select! {
+			_ = sleep(delay) => {}
+			command = control.recv() => match command {
+				Some(Control::Restart(done)) => {
+					failures = 0;
+					let _ = done.send(());
+				}
+				Some(Control::Rebuild(done)) => {
+					needs_build = config.build.is_some();
+					failures = 0;
+					let _ = done.send(());
+				}
+				Some(Control::Stop(done)) => {
+					set_state(ServiceState::Stopped, None);
+					let _ = done.send(());
+					return;
+				}
+				None => return,
+			}
+		}
+	}
+}
+
+fn spawn_child(config: &ProcessConfig, logs: &Arc<ServiceLogs>) -> std::io::Result<Child> {
+	let mut command = Command::new(&config.executable);
+	command.args(config.args.iter());
+	if !config.inherit_env {
+		command.env_clear();
+	}
+	command.envs(config.env.iter());
+	command.current_dir(&config.cwd);
+	command.stdout(Stdio::piped());
+	command.stderr(Stdio::piped());
+	command.kill_on_drop(true);
+
+	let mut child = command.spawn()?;
+	let _stdin = child.stdin.take();
+
+	capture_output(&mut child, logs, config.on_stdout.clone());
+	Ok(child)
+}
+
+/// Runs the build step of the service, its output goes to the service logs
+async fn run_build(
+	name: &str,
+	config: &ProcessConfig,
+	logs: &Arc<ServiceLogs>,
+) -> Result<(), anyhow::Error> {
+	let Some(build) = &config.build else {
+		return Ok(());
+	};
+
+	tracing::info!("Building service {:?} with {:?}", name, build.executable);
+
+	let mut command = Command::new(&build.executable);
+	command.args(build.args.iter());
+	if !build.inherit_env {
+		command.env_clear();
+	}
+	command.envs(build.env.iter());
+	command.current_dir(&build.cwd);
+	command.stdin(Stdio::null());
+	command.stdout(Stdio::piped());
+	command.stderr(Stdio::piped());
+	command.kill_on_drop(true);
+
+	let mut child = command
+		.spawn()
+		.with_context(|| format!("Failed to start the build of service {:?}", name))?;
+
+	capture_output(&mut child, logs, None);
+
+	let status = child.wait().await?;
+	if !status.success() {
+		bail!("Build of service {:?} failed with {}", name, status);
+	}
+
+	Ok(())
+}
+
+fn capture_output(
+	child: &mut Child,
+	logs: &Arc<ServiceLogs>,
+	on_stdout: Option<StdoutCallback>,
+) {
+	if let Some(stdout) = child.stdout.take() {
+		tokio::spawn(read_lines(stdout, LogStream::Stdout, logs.clone(), on_stdout));
+	}
+
+	if let Some(stderr) = child.stderr.take() {
+		tokio::spawn(read_lines(stderr, LogStream::Stderr, logs.clone(), None));
+	}
+}
+
+async fn read_lines(
+	output: impl AsyncRead + Unpin,
+	stream: LogStream,
+	logs: Arc<ServiceLogs>,
+	on_stdout: Option<StdoutCallback>,
+) {
+	let mut lines = BufReader::new(output).lines();
+	loop {
+		match lines.next_line().await {
+			Ok(Some(line)) => {
+				if let Some(callback) = &on_stdout {
+					let message = RuntimeMessage::ProcessLog(callback.service, line.clone());
+					let _ = callback.runtime.send(message).await;
+				}
+
+				logs.push(stream, line);
+			}
+			Ok(None) => break,
+			Err(e) => {
+				tracing::warn!("Failed to read service output: {}", e);
+				break;
+			}
+		}
+	}
+}
+
+/// Asks the process to terminate and kills it if it is still running after the timeout
+async fn stop_child(name: &str, child: &mut Child, timeout: Duration) {
+	if let Some(pid) = child.id() {
+		// SAFETY: the pid belongs to a child that has not been reaped yet
+		let sent = unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } == 0;
+
+		if sent {
+			match tokio::time::timeout(timeout, child.wait()).await {
+				Ok(_) => return,
+				Err(_) => tracing::warn!("Service {:?} did not stop in {:?}", name, timeout),
+			}
+		}
+	}
+
+	if let Err(e) = child.kill().await {
+		tracing::warn!("Failed to kill service {:?}: {}", name, e);
 	}
 }
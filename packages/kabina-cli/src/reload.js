@@ -0,0 +1,25 @@
+// Injected into HTML pages served by kabina, reloads the page when served files change
+(() => {
+  const source = new EventSource("/__kabina/events");
+
+  source.addEventListener("change", (event) => {
+    const { paths } = JSON.parse(event.data);
+    const styles = [...document.querySelectorAll('link[rel="stylesheet"]')];
+
+    const swapped = paths.every((path) => {
+      const link = styles.find((l) => new URL(l.href).pathname === path);
+      if (!link) {
+        return false;
+      }
+
+      const url = new URL(link.href);
+      url.searchParams.set("kabina", Date.now().toString());
+      link.href = url.toString();
+      return true;
+    });
+
+    if (!swapped) {
+      location.reload();
+    }
+  });
+})();
@@ -1,13 +1,22 @@
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
 use std::thread::JoinHandle;
 
+use anyhow::anyhow;
 use kabina_db::runtime::Runtime;
-use kabina_db::{Schema, SharedDatabase};
+use kabina_db::{
+	transform_identity, Cause, Database, File, Outcome, Schema, Service, SharedDatabase,
+	TransformApply,
+};
 use kabina_rt::DenoRuntime;
+use parking_lot::Mutex;
 use tokio::sync::mpsc::{channel, Sender};
 use tokio::sync::oneshot;
 use url::Url;
 
+use crate::drive::jobs_limit;
+
 pub struct RuntimeChannel {
 	handle: JoinHandle<()>,
 	sender: Sender<RuntimeMessage>,
@@ -15,7 +24,11 @@ pub struct RuntimeChannel {
 
 #[derive(Debug)]
 pub enum RuntimeMessage {
-	Schema(oneshot::Sender<Schema>),
+	/// The schema of the runtime, or why its module failed to load
+	Schema(oneshot::Sender<Result<Schema, anyhow::Error>>),
+	Transform(TransformApply, oneshot::Sender<Outcome<File>>),
+	/// A line written by the service to stdout
+	ProcessLog(Service, String),
 }
 
 #[derive(Default)]
@@ -39,13 +52,62 @@ impl RuntimeManager {
 					.build()
 					.unwrap();
 
-				let mut deno_rt = tokio_rt.block_on(DenoRuntime::new(db));
-				let schema = tokio_rt.block_on(deno_rt.load_schema(url.clone()));
+				let mut deno_rt = tokio_rt.block_on(DenoRuntime::new(db.clone()));
+				let schema = match tokio_rt.block_on(deno_rt.load_schema(url.clone())) {
+					Ok(schema) => schema,
+					Err(e) => {
+						tracing::error!("Failed to load schema {}: {:#}", url, e);
+
+						// Whoever asks before the manager forgets the runtime gets the error
+						while let Some(msg) = tokio_rt.block_on(rx.recv()) {
+							if let RuntimeMessage::Schema(tx) = msg {
+								let _ = tx.send(Err(error_copy(&e)));
+							}
+						}
+						return;
+					}
+				};
+				let functions = deno_rt.transform_functions(schema);
+
+				// Started with the first transform, schemas without transforms do not pay for it
+				let mut pool: Option<TransformPool> = None;
 
 				while let Some(msg) = tokio_rt.block_on(rx.recv()) {
 					match msg {
 						RuntimeMessage::Schema(rx) => {
-							let _ = rx.send(schema);
+							let _ = rx.send(Ok(schema));
+						}
+						RuntimeMessage::Transform(task, rx) => {
+							let identity = transform_identity(&*db.lock(), task.transform);
+							let job = TransformJob {
+								task,
+								identity,
+								reply: rx,
+							};
+
+							// Workers run the function of their own evaluation of the module,
+							// which is only correct for pure transforms. Transforms without an
+							// unambiguous identity fall back to the functions of this runtime.
+							let job = if functions.contains_key(&job.identity) {
+								let pool = pool.get_or_insert_with(|| {
+									TransformPool::spawn(&db, &url, jobs_limit())
+								});
+								match pool.run(job) {
+									Ok(()) => continue,
+									Err(job) => job,
+								}
+							} else {
+								job
+							};
+
+							let result = tokio_rt.block_on(deno_rt.transform(&job.task));
+							let _ = job.reply.send(result);
+						}
+						RuntimeMessage::ProcessLog(service, line) => {
+							let result = tokio_rt.block_on(deno_rt.process_log(service, line));
+							if let Err(e) = result {
+								tracing::warn!("{}", e);
+							}
 						}
 					}
 				}
@@ -72,4 +134,117 @@ impl RuntimeManager {
 
 		sender
 	}
+
+	/// Drops the runtime of the schema, it stops once nobody else holds its channel
+	pub fn forget(&mut self, url: &Url) {
+		self.runtimes.remove(url);
+	}
+}
+
+/// Error with the same chain of messages, for every request waiting for it
+fn error_copy(error: &anyhow::Error) -> anyhow::Error {
+	let mut causes = error.chain().rev().map(ToString::to_string);
+	let root = anyhow!(causes.next().unwrap_or_default());
+	causes.fold(root, |error, cause| error.context(cause))
+}
+
+struct TransformJob {
+	task: TransformApply,
+	identity: String,
+	reply: oneshot::Sender<Outcome<File>>,
+}
+
+/// Runtimes that evaluate the schema again on their own database and run transforms of
+/// the primary runtime in parallel. Functions are matched by transform identity, so they
+/// must be pure: a worker only sees the state the module has right after evaluation.
+///
+/// Every worker evaluates the module once, so workers are only started while queued jobs
+/// find no idle one, up to `limit`.
+struct TransformPool {
+	db: SharedDatabase,
+	url: Url,
+	jobs: mpsc::Sender<TransformJob>,
+	rx: Arc<Mutex<mpsc::Receiver<TransformJob>>>,
+	/// Workers waiting for a job
+	idle: Arc<AtomicUsize>,
+	workers: usize,
+	limit: usize,
+}
+
+impl TransformPool {
+	fn spawn(db: &SharedDatabase, url: &Url, limit: usize) -> Self {
+		let (jobs, rx) = mpsc::channel();
+
+		TransformPool {
+			db: db.clone(),
+			url: url.clone(),
+			jobs,
+			rx: Arc::new(Mutex::new(rx)),
+			idle: Default::default(),
+			workers: 0,
+			limit,
+		}
+	}
+
+	/// Queues the job, gives it back when no worker is left to run it
+	fn run(&mut self, job: TransformJob) -> Result<(), TransformJob> {
+		if self.idle.load(Ordering::Acquire) == 0 && self.workers < self.limit {
+			self.workers += 1;
+			let (workers, url) = (self.workers, &self.url);
+			tracing::info!("Starting transform worker {} for {}", workers, url);
+
+			let db = self.db.clone();
+			let url = self.url.clone();
+			let rx = self.rx.clone();
+			let idle = self.idle.clone();
+			std::thread::spawn(move || transform_worker(db, url, rx, idle));
+		}
+
+		self.jobs.send(job).map_err(|e| e.0)
+	}
+}
+
+fn transform_worker(
+	db: SharedDatabase,
+	url: Url,
+	jobs: Arc<Mutex<mpsc::Receiver<TransformJob>>>,
+	idle: Arc<AtomicUsize>,
+) {
+	let tokio_rt = tokio::runtime::Builder::new_current_thread()
+		.enable_all()
+		.build()
+		.unwrap();
+
+	// The worker database only holds the schema evaluated by this runtime
+	let own = Arc::new(Mutex::new(Database::new()));
+	let mut deno_rt = tokio_rt.block_on(DenoRuntime::new(own));
+
+	// Jobs taken by a worker that failed to load are reported as not registered
+	let functions = match tokio_rt.block_on(deno_rt.load_schema(url.clone())) {
+		Ok(schema) => deno_rt.transform_functions(schema),
+		Err(e) => {
+			tracing::error!("Transform worker failed to load {}: {:#}", url, e);
+			HashMap::new()
+		}
+	};
+
+	loop {
+		idle.fetch_add(1, Ordering::Release);
+		let job = jobs.lock().recv();
+		idle.fetch_sub(1, Ordering::Release);
+
+		let Ok(job) = job else {
+			break;
+		};
+
+		let result = match functions.get(&job.identity) {
+			Some(id) => tokio_rt.block_on(deno_rt.transform_function(*id, &job.task, &db)),
+			None => Err(Cause::from_anyhow(anyhow!(
+				"Transform {} is not registered in the worker",
+				job.identity
+			))),
+		};
+
+		let _ = job.reply.send(result);
+	}
 }
@@ -1,15 +1,35 @@
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-use kabina_db::{binary_resolve, AsId, BinaryRuntimeResolved, SharedDatabase};
-use kabina_rpc::{Kabina, KabinaObserverClient};
+use anyhow::{anyhow, bail};
+use kabina_db::{
+	binary_resolve, service_inputs_revision, services_start_order, AsId, BinaryRuntimeResolved,
+	Port, Schema, Service, SharedDatabase,
+};
+use kabina_rpc::{
+	DaemonStatus, Diagnostic, Event, Kabina, KabinaObserverClient, QueryError, RunError,
+	ServerInfo, ServiceError, ServiceInfo, ServiceState, Severity,
+};
 use parking_lot::Mutex;
 use tarpc::context::{current, Context};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc::Sender;
 use tokio::sync::oneshot;
+use tokio::time::sleep;
 use url::Url;
 
-use crate::drive::drive;
-use crate::process::{ProcessConfig, ProcessMananger};
+use crate::daemon::daemon_socket_path;
+use crate::diagnostics::diagnostics_reset;
+use crate::drive::{drive, query_error, Cancellation, DriveError};
+use crate::events::{emit, Subscriptions};
+use crate::http::HttpManager;
+use crate::ports::PortAllocator;
+use crate::process::{BuildConfig, Process, ProcessConfig, ProcessMananger, StdoutCallback};
 use crate::runtime::{RuntimeManager, RuntimeMessage};
+use crate::status::{schemas_status, status_reset};
+use crate::watch::WatchManager;
 
 pub const VERSION: u32 = const_random::const_random!(u32);
 
@@ -18,48 +38,259 @@ pub struct KabinaState {
 	pub database: SharedDatabase,
 	pub rtm: Arc<Mutex<RuntimeManager>>,
 	pub process: Arc<Mutex<ProcessMananger>>,
+	pub watcher: Arc<Mutex<WatchManager>>,
+	pub http: Arc<Mutex<HttpManager>>,
+	pub ports: Arc<Mutex<PortAllocator>>,
+	/// Schema runs in progress by the id the client picked
+	pub runs: Arc<Mutex<HashMap<u64, RunControl>>>,
+}
+
+pub struct RunControl {
+	cancel: Cancellation,
+	/// Stop the services started by the run once it is cancelled
+	stop_services: bool,
+}
+
+impl KabinaState {
+	/// Evaluates the schema module, registers it, starts watching its roots and serving it
+	pub async fn schema_load(
+		&self,
+		url: Url,
+	) -> Result<(Sender<RuntimeMessage>, Schema), anyhow::Error> {
+		// The client running the schema sees its diagnostics, even if they were reported before
+		diagnostics_reset(&url);
+		status_reset(&url);
+
+		if let Err(e) = self.database.lock().project_open(&url) {
+			tracing::warn!("Failed to open the project database of {}: {:#}", url, e);
+		}
+
+		let channel = {
+			let mut rtm = self.rtm.lock();
+			rtm.spawn(self.database.clone(), url.clone())
+		};
+
+		let schema = {
+			let (tx, rx) = oneshot::channel();
+			let gone = || anyhow!("Runtime for {} is gone", url);
+			channel
+				.send(RuntimeMessage::Schema(tx))
+				.await
+				.map_err(|_| gone())?;
+			rx.await.map_err(|_| gone())?
+		};
+
+		// The module is evaluated again by the next load, once it is fixed
+		let schema = match schema {
+			Ok(schema) => schema,
+			Err(e) => {
+				self.rtm.lock().forget(&url);
+				return Err(e);
+			}
+		};
+
+		let db = &self.database;
+		if let Err(e) = self.ports_allocate(&url, schema) {
+			tracing::warn!("Failed to allocate ports: {}", e);
+		}
+
+		if let Err(e) = db.lock().schema_add(url, schema) {
+			tracing::warn!("Failed to register schema: {}", e);
+		}
+
+		self.watcher.lock().watch(db, schema);
+
+		let servers = schema.servers(&*db.lock()).clone();
+		for server in servers.iter() {
+			let applied = self.watcher.lock().subscribe();
+			let started = self.http.lock().start(
+				db.clone(),
+				channel.clone(),
+				schema,
+				*server,
+				applied,
+			);
+
+			if let Err(e) = started {
+				tracing::warn!("Failed to start server: {}", e);
+			}
+		}
+
+		Ok((channel, schema))
+	}
+
+	/// Replaces automatic ports of the schema services and servers with free ones
+	fn ports_allocate(&self, url: &Url, schema: Schema) -> std::io::Result<()> {
+		let mut db = self.database.lock();
+		let mut allocator = self.ports.lock();
+
+		let services = schema.services(&*db).clone();
+		for service in services.iter() {
+			let mut ports = service.ports(&*db);
+			if !ports.values().any(|p| *p == Port::Auto) {
+				continue;
+			}
+
+			let name = service.name(&*db);
+			for (key, port) in ports.iter_mut().filter(|(_, p)| **p == Port::Auto) {
+				*port = Port::Fixed(allocator.allocate(url, format!("service {}.{}", name, key))?);
+			}
+
+			service.set_ports(&mut *db).to(ports);
+		}
+
+		let servers = schema.servers(&*db).clone();
+		for server in servers.iter() {
+			if server.port(&*db) == Some(Port::Auto) {
+				let port = allocator.allocate(url, format!("server {}", server.name(&*db)))?;
+				server.set_port(&mut *db).to(Some(Port::Fixed(port)));
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Rebuilds and restarts the service whenever its input files change
+	async fn service_reload(
+		self,
+		channel: Sender<RuntimeMessage>,
+		schema: Schema,
+		service: Service,
+		process: Arc<Process>,
+		mut revision: u64,
+	) {
+		let mut applied = self.watcher.lock().subscribe();
+
+		loop {
+			match applied.recv().await {
+				Ok(()) | Err(RecvError::Lagged(_)) => {}
+				Err(RecvError::Closed) => break,
+			}
+
+			// Services started again get a new process and a new watcher
+			let current = self.process.lock().get(process.id);
+			if !current.map_or(false, |p| Arc::ptr_eq(&p, &process)) {
+				break;
+			}
+
+			if process.state() == ServiceState::Stopped {
+				continue;
+			}
+
+			let next = drive!(
+				channel,
+				service_inputs_revision(self.database, schema, service)
+			);
+			let next = match next.await {
+				Ok(next) => next,
+				Err(e) => {
+					query_report(&schema.url(&*self.database.lock()), &e);
+					continue;
+				}
+			};
+
+			if next == revision {
+				continue;
+			}
+
+			revision = next;
+			tracing::info!("Inputs of service {:?} changed", process.name);
+			process.rebuild().await;
+		}
+	}
 }
 
 #[derive(Clone)]
 pub struct KabinaServer {
 	pub peer: KabinaObserverClient,
 	pub state: KabinaState,
+	/// Schemas run by the client of the connection, it receives their events
+	pub subscriptions: Subscriptions,
 }
 
-#[tarpc::server]
-impl Kabina for KabinaServer {
-	async fn hello(self, _: Context, name: String) -> String {
-		format!("Hello, {name}! You are connected")
-	}
+impl KabinaServer {
+	/// Waits until the services the given one depends on are ready
+	async fn wait_dependencies(
+		&self,
+		name: &str,
+		depends_on: &[Service],
+	) -> Result<(), anyhow::Error> {
+		for dependency in depends_on {
+			let process = self.state.process.lock().get(dependency.as_id().into());
+			let Some(process) = process else {
+				let dependency = dependency.name(&*self.state.database.lock());
+				bail!("Service {:?} depends on {:?} which is not running", name, dependency);
+			};
 
-	async fn version(self, _: Context) -> String {
-		VERSION.to_string()
-	}
+			tracing::info!("Service {:?} is waiting for {:?}", name, process.name);
+			if let Err(state) = process.wait_ready().await {
+				bail!(
+					"Service {:?} is not started, its dependency {:?} is {:?}",
+					name,
+					process.name,
+					state
+				);
+			}
+		}
 
-	async fn terminate(self, _: Context) {
-		let _ = std::fs::remove_file("/tmp/kabina.sock");
-		std::process::exit(0)
+		Ok(())
 	}
 
-	async fn schema_run(self, _: Context, url: Url) {
-		tracing::info!("[Method] Kabina::schema_run");
-
-		let mut channel = {
-			let mut rtm = self.state.rtm.lock();
-			rtm.spawn(self.state.database.clone(), url)
+	/// Loads the schema and starts its services, returns the processes started and the
+	/// queries that failed
+	async fn run(&self, url: Url, cancel: &Cancellation) -> (Vec<Arc<Process>>, Vec<QueryError>) {
+		let mut started = Vec::new();
+		let mut failed = Vec::new();
+		// Loading is cancelled while the module is evaluated, before anything is registered
+		let loaded = tokio::select! {
+			loaded = self.state.schema_load(url.clone()) => loaded,
+			_ = cancel.cancelled() => return (started, failed),
 		};
 
-		let schema = {
-			let (tx, rx) = oneshot::channel();
-			channel.send(RuntimeMessage::Schema(tx)).await.unwrap();
-			rx.await.unwrap()
+		let (channel, schema) = match loaded {
+			Ok(loaded) => loaded,
+			Err(e) => {
+				let error = query_error("schema_load", &e);
+				query_report(&url, &error);
+				failed.push(error);
+				return (started, failed);
+			}
 		};
 
 		let db = &self.state.database;
-		let services = schema.services(&*db.lock()).clone();
+		let services = match services_start_order(&*db.lock(), schema) {
+			Ok(services) => services,
+			Err(e) => {
+				tracing::error!("{}", e);
+				let diagnostic = Diagnostic::message(Severity::Error, e.to_string());
+				emit(&url, Event::Diagnostic(diagnostic));
+				return (started, failed);
+			}
+		};
 
-		for service in services.iter() {
-			tracing::info!("Running service: {}", service.name(&*db.lock()));
+		for service in services {
+			if cancel.is_cancelled() {
+				break;
+			}
+
+			let (name, depends_on) = {
+				let db = db.lock();
+				(service.name(&*db), service.depends_on(&*db))
+			};
+
+			let waited = tokio::select! {
+				waited = self.wait_dependencies(&name, &depends_on) => waited,
+				_ = cancel.cancelled() => break,
+			};
+
+			if let Err(e) = waited {
+				tracing::error!("{}", e);
+				let diagnostic = Diagnostic::message(Severity::Error, e.to_string());
+				emit(&url, Event::Diagnostic(diagnostic));
+				continue;
+			}
+
+			tracing::info!("Running service: {}", name);
 
 			let binary = service.binary(&*db.lock());
 
@@ -70,30 +301,365 @@ impl Kabina for KabinaServer {
 			}
 
 			let binary_meta =
-				drive!(channel, binary_resolve(self.state.database, schema, binary)).await;
+				drive!(channel, binary_resolve(self.state.database, schema, binary), cancel).await;
+
+			let binary_meta = match binary_meta {
+				Ok(binary_meta) => binary_meta,
+				Err(DriveError::Cancelled) => break,
+				Err(DriveError::Failed(e)) => {
+					query_report(&url, &e);
+					failed.push(e);
+					continue;
+				}
+			};
 
 			match binary_meta {
 				BinaryRuntimeResolved::Native {
 					executable,
 					env,
 					args,
+					cwd,
+					inherit_env,
+					..
 				} => {
+					let (restart, logs, readiness, build, stop_timeout, inputs) = {
+						let db = db.lock();
+						(
+							service.restart(&*db),
+							service.logs(&*db),
+							service.readiness(&*db),
+							service.build(&*db),
+							service.stop_timeout(&*db),
+							service.inputs(&*db),
+						)
+					};
+
+					let (ports, default_port) = {
+						let db = db.lock();
+						(service.fixed_ports(&*db), service.default_port(&*db))
+					};
+
+					// Variables set by the binary take precedence over the injected ports
+					let env = ports_env(&ports, default_port).into_iter().chain(env).collect();
+
+					let build = match build {
+						Some(build) => {
+							let resolved = drive!(
+								channel,
+								binary_resolve(self.state.database, schema, build),
+								cancel
+							);
+
+							let resolved = match resolved.await {
+								Ok(resolved) => resolved,
+								Err(DriveError::Cancelled) => break,
+								Err(DriveError::Failed(e)) => {
+									query_report(&url, &e);
+									failed.push(e);
+									continue;
+								}
+							};
+
+							let BinaryRuntimeResolved::Native {
+								executable,
+								env,
+								args,
+								cwd,
+								inherit_env,
+								..
+							} = resolved;
+
+							Some(BuildConfig {
+								executable,
+								env,
+								args,
+								cwd,
+								inherit_env,
+							})
+						}
+						None => None,
+					};
+
+					// Inputs are resolved before the first start, so the service sees fresh files
+					let revision = if inputs.is_empty() {
+						None
+					} else {
+						let revision = drive!(
+							channel,
+							service_inputs_revision(self.state.database, schema, service),
+							cancel
+						);
+
+						match revision.await {
+							Ok(revision) => Some(revision),
+							Err(DriveError::Cancelled) => break,
+							Err(DriveError::Failed(e)) => {
+								query_report(&url, &e);
+								failed.push(e);
+								continue;
+							}
+						}
+					};
+
 					tracing::info!("Spawning executable: {:?}", executable);
-					self.state.process.lock().spawn(
+
+					let on_stdout = logs.stdout_callback.then(|| StdoutCallback {
+						runtime: channel.clone(),
+						service,
+					});
+
+					let process = ProcessMananger::spawn(
+						&self.state.process,
 						service.as_id().into(),
+						name,
 						ProcessConfig {
+							schema: url.clone(),
 							executable,
 							env,
 							args,
+							cwd,
+							inherit_env,
+							log_file: logs.file,
+							on_stdout,
+							readiness,
+							build,
+							stop_timeout: Duration::from_millis(stop_timeout),
+							ports,
 						},
-					);
+						restart,
+						depends_on.iter().map(|d| d.as_id().into()).collect(),
+					)
+					.await;
+
+					started.push(process.clone());
+
+					if let Some(revision) = revision {
+						let reload = self.state.clone().service_reload(
+							channel.clone(),
+							schema,
+							service,
+							process,
+							revision,
+						);
+
+						tokio::spawn(reload);
+					}
 				}
 			}
 		}
 
-		self.peer
-			.log(current(), "FINISH EXECUTION".into())
+		(started, failed)
+	}
+}
+
+#[tarpc::server]
+impl Kabina for KabinaServer {
+	async fn hello(self, _: Context, name: String) -> String {
+		format!("Hello, {name}! You are connected")
+	}
+
+	async fn version(self, _: Context) -> String {
+		VERSION.to_string()
+	}
+
+	async fn status(self, _: Context) -> DaemonStatus {
+		let schemas = schemas_status(&self.state.database);
+
+		DaemonStatus {
+			version: VERSION.to_string(),
+			schemas,
+			processes: self.state.process.lock().list(),
+			servers: self.state.http.lock().list(),
+		}
+	}
+
+	async fn terminate(self, _: Context) {
+		let stopping = self.state.process.lock().stop_order(None);
+		for process in stopping {
+			tracing::info!("Stopping service {:?}", process.name);
+			process.stop().await;
+		}
+
+		if let Ok(socket) = daemon_socket_path() {
+			let _ = std::fs::remove_file(socket);
+		}
+		std::process::exit(0)
+	}
+
+	async fn schema_run(self, ctx: Context, url: Url, run: u64) -> Result<(), RunError> {
+		tracing::info!("[Method] Kabina::schema_run");
+
+		self.subscriptions.lock().insert(url.clone());
+
+		let cancel = Cancellation::default();
+		match self.state.runs.lock().entry(run) {
+			Entry::Occupied(_) => return Err(RunError::Duplicate(run)),
+			Entry::Vacant(entry) => {
+				entry.insert(RunControl {
+					cancel: cancel.clone(),
+					stop_services: false,
+				});
+			}
+		}
+
+		// The request is dropped at its deadline, the run is cancelled before that to clean up
+		let remaining = ctx.deadline.duration_since(SystemTime::now()).unwrap_or_default();
+		let timer = tokio::spawn({
+			let cancel = cancel.clone();
+			async move {
+				sleep(remaining).await;
+				tracing::warn!("Run {} reached its deadline", run);
+				cancel.cancel();
+			}
+		});
+
+		let server = self.clone();
+		let running = tokio::spawn(async move {
+			let (started, failed) = server.run(url.clone(), &cancel).await;
+			timer.abort();
+
+			let control = server.state.runs.lock().remove(&run);
+			if !cancel.is_cancelled() {
+				return if failed.is_empty() {
+					Ok(())
+				} else {
+					Err(RunError::Failed(failed))
+				};
+			}
+
+			let message = format!("Run {} is cancelled", run);
+			let diagnostic = Diagnostic::message(Severity::Info, message);
+			emit(&url, Event::Diagnostic(diagnostic));
+
+			if control.map_or(false, |c| c.stop_services) {
+				// Dependents were started last, so they are stopped first
+				for process in started.iter().rev() {
+					tracing::info!("Stopping service {:?}", process.name);
+					process.stop().await;
+				}
+			}
+
+			Err(RunError::Cancelled)
+		});
+
+		// A panic of the run is reported to the client, the daemon keeps running
+		let result = running
 			.await
-			.unwrap();
+			.unwrap_or_else(|e| Err(RunError::Aborted(e.to_string())));
+
+		let _ = self.peer.log(current(), "FINISH EXECUTION".into()).await;
+		result
+	}
+
+	async fn cancel(self, _: Context, run: u64, stop_services: bool) -> bool {
+		tracing::info!("[Method] Kabina::cancel {}", run);
+
+		let mut runs = self.state.runs.lock();
+		let Some(control) = runs.get_mut(&run) else {
+			return false;
+		};
+
+		control.stop_services = stop_services;
+		control.cancel.cancel();
+		true
+	}
+
+	async fn service_list(self, _: Context) -> Vec<ServiceInfo> {
+		self.state.process.lock().list()
+	}
+
+	async fn server_list(self, _: Context) -> Vec<ServerInfo> {
+		self.state.http.lock().list()
 	}
+
+	async fn service_stop(self, _: Context, name: String) -> Result<(), ServiceError> {
+		tracing::info!("[Method] Kabina::service_stop {:?}", name);
+
+		let process = self.state.process.lock().find(&name)?;
+
+		// Services depending on this one are stopped first
+		let stopping = self.state.process.lock().stop_order(Some(process.id));
+		for process in stopping {
+			tracing::info!("Stopping service {:?}", process.name);
+			process.stop().await;
+		}
+
+		Ok(())
+	}
+
+	async fn service_restart(self, _: Context, name: String) -> Result<(), ServiceError> {
+		tracing::info!("[Method] Kabina::service_restart {:?}", name);
+
+		let process = self.state.process.lock().find(&name)?;
+		process.restart().await;
+		Ok(())
+	}
+
+	async fn service_logs(
+		self,
+		_: Context,
+		name: String,
+		follow: bool,
+	) -> Result<(), ServiceError> {
+		tracing::info!("[Method] Kabina::service_logs {:?}", name);
+
+		let process = self.state.process.lock().find(&name)?;
+		let (buffered, mut rx) = process.logs.tail();
+
+		for line in buffered {
+			if self.peer.service_log(current(), line).await.is_err() {
+				return Ok(());
+			}
+		}
+
+		if follow {
+			// Streams until the observer goes away
+			tokio::spawn(async move {
+				loop {
+					let line = match rx.recv().await {
+						Ok(line) => line,
+						Err(RecvError::Lagged(skipped)) => {
+							tracing::warn!("Skipped {} lines of {:?}", skipped, name);
+							continue;
+						}
+						Err(RecvError::Closed) => break,
+					};
+
+					if self.peer.service_log(current(), line).await.is_err() {
+						break;
+					}
+				}
+			});
+		}
+
+		Ok(())
+	}
+}
+
+/// `PORT` with the default port and `KABINA_PORT_<NAME>` for every port of the service
+fn ports_env(ports: &BTreeMap<String, u16>, default: Option<u16>) -> BTreeMap<String, String> {
+	let mut env = BTreeMap::new();
+	if let Some(port) = default {
+		env.insert("PORT".to_owned(), port.to_string());
+	}
+
+	for (name, port) in ports {
+		let name = name
+			.chars()
+			.map(|c| match c {
+				'a'..='z' | 'A'..='Z' | '0'..='9' => c.to_ascii_uppercase(),
+				_ => '_',
+			})
+			.collect::<String>();
+
+		env.insert(format!("KABINA_PORT_{}", name), port.to_string());
+	}
+
+	env
+}
+
+/// Reports the failed query to the connected clients, the run goes on without it
+fn query_report(schema: &Url, error: &QueryError) {
+	let diagnostic = Diagnostic::message(Severity::Error, error.to_string());
+	emit(schema, Event::Diagnostic(diagnostic));
 }
@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use kabina_db::{
+	Binary, Cause, Collection, Db, FileGroup, Outcome, Schema, Service, SharedDatabase, Transform,
+};
+use kabina_rpc::{DaemonStatus, NodeState, NodeStatus, SchemaStatus};
+use parking_lot::Mutex;
+use url::Url;
+
+use crate::diagnostics::diagnostics_reported;
+
+/// Outcome of the last drive of each node by schema, `kabina status` reports them without
+/// running any query
+static DRIVEN: OnceLock<Mutex<HashMap<Url, DrivenNodes>>> = OnceLock::new();
+
+type DrivenNodes = HashMap<(NodeKind, String), NodeState>;
+
+fn driven() -> &'static Mutex<HashMap<Url, DrivenNodes>> {
+	DRIVEN.get_or_init(Default::default)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NodeKind {
+	FileGroup,
+	Transform,
+	Collection,
+	Service,
+}
+
+/// Arguments of a driven query, name the nodes its outcome is reported for
+pub trait StatusNodes {
+	fn status_nodes(&self, db: &dyn Db, schema: Schema) -> Vec<(NodeKind, String)>;
+}
+
+impl StatusNodes for () {
+	fn status_nodes(&self, _db: &dyn Db, _schema: Schema) -> Vec<(NodeKind, String)> {
+		Vec::new()
+	}
+}
+
+impl<T: StatusNodes> StatusNodes for (T,) {
+	fn status_nodes(&self, db: &dyn Db, schema: Schema) -> Vec<(NodeKind, String)> {
+		self.0.status_nodes(db, schema)
+	}
+}
+
+impl StatusNodes for FileGroup {
+	fn status_nodes(&self, db: &dyn Db, _schema: Schema) -> Vec<(NodeKind, String)> {
+		vec![(NodeKind::FileGroup, self.name(db))]
+	}
+}
+
+impl StatusNodes for Transform {
+	fn status_nodes(&self, db: &dyn Db, _schema: Schema) -> Vec<(NodeKind, String)> {
+		vec![(NodeKind::Transform, self.name(db))]
+	}
+}
+
+impl StatusNodes for Collection {
+	fn status_nodes(&self, db: &dyn Db, _schema: Schema) -> Vec<(NodeKind, String)> {
+		vec![(NodeKind::Collection, self.name(db))]
+	}
+}
+
+impl StatusNodes for Service {
+	fn status_nodes(&self, db: &dyn Db, _schema: Schema) -> Vec<(NodeKind, String)> {
+		vec![(NodeKind::Service, self.name(db))]
+	}
+}
+
+/// Services running the binary, build steps of other services are not reported
+impl StatusNodes for Binary {
+	fn status_nodes(&self, db: &dyn Db, schema: Schema) -> Vec<(NodeKind, String)> {
+		schema
+			.services(db)
+			.iter()
+			.filter(|s| s.binary(db) == *self)
+			.map(|s| (NodeKind::Service, s.name(db)))
+			.collect()
+	}
+}
+
+/// Remembers the outcome of a query `drive!` resolved, pending until its tasks are done
+pub fn status_record<T>(
+	db: &dyn Db,
+	schema: Schema,
+	args: &impl StatusNodes,
+	outcome: &Outcome<T>,
+) {
+	let nodes = args.status_nodes(db, schema);
+	if nodes.is_empty() {
+		return;
+	}
+
+	let state = match outcome {
+		Ok(_) => NodeState::Ok,
+		Err(Cause::Pending) => NodeState::Pending,
+		Err(Cause::Error(e)) => NodeState::Error(format!("{:#}", e.0)),
+	};
+
+	let mut driven = driven().lock();
+	let driven = driven.entry(schema.url(db)).or_default();
+	for node in nodes {
+		driven.insert(node, state.clone());
+	}
+}
+
+/// Forgets the outcomes of the schema, its nodes are pending until it is driven again
+pub fn status_reset(schema: &Url) {
+	driven().lock().remove(schema);
+}
+
+/// State of every loaded schema from the last drive of its nodes. Only the declared nodes
+/// are read from the database, nodes which were never driven are pending
+pub fn schemas_status(db: &SharedDatabase) -> Vec<SchemaStatus> {
+	let db = db.lock();
+	let db = &*db;
+	let driven = driven().lock();
+
+	db.schema_loaded()
+		.into_iter()
+		.map(|(url, schema)| {
+			let driven = driven.get(&url);
+			let file_groups = schema.file_groups(db).iter().map(|g| g.name(db));
+			let transforms = schema.transforms(db).iter().map(|t| t.name(db));
+			let collections = schema.collections(db).iter().map(|c| c.name(db));
+			let services = schema.services(db).iter().map(|s| s.name(db));
+
+			SchemaStatus {
+				diagnostics: diagnostics_reported(&url),
+				url,
+				file_groups: nodes(driven, NodeKind::FileGroup, file_groups),
+				transforms: nodes(driven, NodeKind::Transform, transforms),
+				collections: nodes(driven, NodeKind::Collection, collections),
+				services: nodes(driven, NodeKind::Service, services),
+			}
+		})
+		.collect()
+}
+
+/// Sorted states of the nodes of a kind
+fn nodes(
+	driven: Option<&DrivenNodes>,
+	kind: NodeKind,
+	names: impl Iterator<Item = String>,
+) -> Vec<NodeStatus> {
+	let mut nodes = names
+		.map(|name| {
+			let state = driven.and_then(|d| d.get(&(kind, name.clone())));
+			NodeStatus {
+				state: state.cloned().unwrap_or(NodeState::Pending),
+				name,
+			}
+		})
+		.collect::<Vec<_>>();
+
+	nodes.sort_by(|a, b| a.name.cmp(&b.name));
+	nodes
+}
+
+/// Renders the status for humans, `--json` prints it as is
+pub fn status_print(status: &DaemonStatus) {
+	println!("Daemon version {}", status.version);
+
+	for schema in &status.schemas {
+		println!();
+		println!("Schema {}", schema.url);
+
+		let groups = [
+			("file group", &schema.file_groups),
+			("transform", &schema.transforms),
+			("collection", &schema.collections),
+			("service", &schema.services),
+		];
+
+		for (kind, nodes) in groups {
+			for node in nodes {
+				let state = match &node.state {
+					NodeState::Pending => "pending".to_owned(),
+					NodeState::Ok => "ok".to_owned(),
+					NodeState::Error(e) => format!("error: {}", e),
+				};
+
+				println!("  {:<12}{:<24}{}", kind, node.name, state);
+			}
+		}
+
+		if !schema.diagnostics.is_empty() {
+			println!();
+			for diagnostic in &schema.diagnostics {
+				println!("  {}", diagnostic.to_string().replace('\n', "\n  "));
+			}
+		}
+	}
+
+	if !status.processes.is_empty() {
+		println!();
+		println!("Processes");
+
+		for info in &status.processes {
+			println!(
+				"  {:<24}{:<10}{:<12}{:?}",
+				info.name,
+				info.pid.map_or("-".to_owned(), |p| p.to_string()),
+				info.uptime.map_or("-".to_owned(), uptime),
+				info.state,
+			);
+		}
+	}
+
+	if !status.servers.is_empty() {
+		println!();
+		println!("Servers");
+
+		for server in &status.servers {
+			println!("  {:<24}{}", server.name, server.url);
+		}
+	}
+}
+
+fn uptime(seconds: u64) -> String {
+	match seconds {
+		0..=59 => format!("{}s", seconds),
+		60..=3599 => format!("{}m{}s", seconds / 60, seconds % 60),
+		_ => format!("{}h{}m", seconds / 3600, seconds % 3600 / 60),
+	}
+}
@@ -0,0 +1,258 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use kabina_db::{
+	binary_file_revision, root_files_apply, roots, BinaryRuntime, FileChange, Schema,
+	SharedDatabase,
+};
+use notify::event::ModifyKind;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use parking_lot::Mutex;
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::time::sleep;
+
+/// Time to wait for more events before applying a batch of changes
+const DEBOUNCE: Duration = Duration::from_millis(50);
+
+type WatchedRoots = Arc<Mutex<BTreeMap<PathBuf, Vec<Schema>>>>;
+
+/// Executables resolved by the drive loop, sent to the watcher of the daemon
+static EXECUTABLES: OnceLock<UnboundedSender<PathBuf>> = OnceLock::new();
+
+/// Env files and executables of the binaries, their directories are watched so edits are
+/// noticed
+#[derive(Default)]
+struct WatchedFiles {
+	paths: BTreeSet<PathBuf>,
+	dirs: BTreeSet<PathBuf>,
+}
+
+pub struct WatchManager {
+	watcher: Arc<Mutex<RecommendedWatcher>>,
+	roots: WatchedRoots,
+	files: Arc<Mutex<WatchedFiles>>,
+	/// Notified after every batch of changes is applied to the database
+	applied: broadcast::Sender<()>,
+}
+
+impl WatchManager {
+	pub fn new(db: SharedDatabase) -> Result<Self, notify::Error> {
+		let (tx, mut rx) = unbounded_channel::<notify::Result<Event>>();
+		let watcher = notify::recommended_watcher(move |event| {
+			let _ = tx.send(event);
+		})?;
+
+		let watcher = Arc::new(Mutex::new(watcher));
+		let roots = WatchedRoots::default();
+		let files = Arc::new(Mutex::new(WatchedFiles::default()));
+		let (applied, _) = broadcast::channel(16);
+
+		let (executables, mut executables_rx) = unbounded_channel();
+		let _ = EXECUTABLES.set(executables);
+
+		tokio::spawn({
+			let watcher = watcher.clone();
+			let roots = roots.clone();
+			let files = files.clone();
+			let applied = applied.clone();
+			async move {
+				loop {
+					let event = tokio::select! {
+						event = rx.recv() => event,
+						Some(path) = executables_rx.recv() => {
+							watch_files(&watcher, &files, [path]);
+							continue;
+						}
+					};
+
+					let Some(event) = event else {
+						break;
+					};
+
+					let mut events = vec![event];
+
+					sleep(DEBOUNCE).await;
+					while let Ok(event) = rx.try_recv() {
+						events.push(event)
+					}
+
+					let changes = events
+						.into_iter()
+						.filter_map(|e| match e {
+							Ok(e) => Some(e),
+							Err(e) => {
+								tracing::warn!("Watcher error: {}", e);
+								None
+							}
+						})
+						.flat_map(event_changes)
+						.collect::<Vec<_>>();
+
+					if apply_changes(&db, &roots, &files, changes) {
+						let _ = applied.send(());
+					}
+				}
+
+				tracing::info!("Watcher is terminated");
+			}
+		});
+
+		Ok(WatchManager {
+			watcher,
+			roots,
+			files,
+			applied,
+		})
+	}
+
+	/// Subscribes to notifications about applied changes
+	pub fn subscribe(&self) -> broadcast::Receiver<()> {
+		self.applied.subscribe()
+	}
+
+	/// Starts watching every root and env file of the schema
+	pub fn watch(&mut self, db: &SharedDatabase, schema: Schema) {
+		self.watch_env_files(db, schema);
+
+		let schema_roots = roots(&*db.lock(), schema);
+		let mut watched = self.roots.lock();
+
+		for root in schema_roots.paths() {
+			let schemas = watched.entry(root.clone()).or_default();
+			if schemas.is_empty() {
+				tracing::info!("Watching {:?}", root);
+				if let Err(e) = self.watcher.lock().watch(root, RecursiveMode::Recursive) {
+					tracing::warn!("Failed to watch {:?}: {}", root, e);
+					continue;
+				}
+			}
+
+			if !schemas.contains(&schema) {
+				schemas.push(schema);
+			}
+		}
+	}
+
+	fn watch_env_files(&mut self, db: &SharedDatabase, schema: Schema) {
+		let paths = {
+			let db = db.lock();
+			let mut paths = Vec::new();
+			for binary in schema.binaries(&*db).iter() {
+				let BinaryRuntime::Native(native) = binary.runtime(&*db);
+				paths.extend(native.env_files.iter().map(|f| f.path(&*db)));
+			}
+			paths
+		};
+
+		watch_files(&self.watcher, &self.files, paths);
+	}
+}
+
+/// Watches the executable of a resolved binary, so replacing it runs the transforms using
+/// it again. Does nothing outside the daemon.
+pub fn watch_executable(path: PathBuf) {
+	if let Some(executables) = EXECUTABLES.get() {
+		let _ = executables.send(path);
+	}
+}
+
+/// Watches the directories of files outside the roots, other files in them are ignored
+fn watch_files(
+	watcher: &Mutex<RecommendedWatcher>,
+	watched: &Mutex<WatchedFiles>,
+	paths: impl IntoIterator<Item = PathBuf>,
+) {
+	let mut watched = watched.lock();
+	for path in paths {
+		let Some(dir) = path.parent() else {
+			continue;
+		};
+
+		if !watched.dirs.contains(dir) {
+			tracing::info!("Watching {:?}", dir);
+			if let Err(e) = watcher.lock().watch(dir, RecursiveMode::NonRecursive) {
+				tracing::warn!("Failed to watch {:?}: {}", dir, e);
+				continue;
+			}
+			watched.dirs.insert(dir.to_owned());
+		}
+
+		watched.paths.insert(path);
+	}
+}
+
+fn event_changes(event: Event) -> Vec<FileChange> {
+	let created = match event.kind {
+		EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(_)) => true,
+		EventKind::Modify(_) | EventKind::Remove(_) => false,
+		_ => return Vec::new(),
+	};
+
+	event
+		.paths
+		.into_iter()
+		.map(|path| {
+			if !path.exists() {
+				FileChange::Removed(path)
+			} else if created {
+				FileChange::Created(path)
+			} else {
+				FileChange::Modified(path)
+			}
+		})
+		.collect()
+}
+
+/// Returns whether any of the changes belonged to a watched root or file
+fn apply_changes(
+	db: &SharedDatabase,
+	roots: &WatchedRoots,
+	files: &Mutex<WatchedFiles>,
+	changes: Vec<FileChange>,
+) -> bool {
+	if changes.is_empty() {
+		return false;
+	}
+
+	let file_changes = {
+		let files = files.lock();
+		changes
+			.iter()
+			.map(|c| c.path())
+			.filter(|p| files.paths.contains(*p))
+			.map(Path::to_path_buf)
+			.collect::<BTreeSet<_>>()
+	};
+
+	let mut by_root: BTreeMap<(PathBuf, Schema), Vec<FileChange>> = BTreeMap::new();
+
+	for (root, schemas) in roots.lock().iter() {
+		for change in changes.iter().filter(|c| c.path().starts_with(root)) {
+			for schema in schemas {
+				by_root
+					.entry((root.clone(), *schema))
+					.or_default()
+					.push(change.clone());
+			}
+		}
+	}
+
+	let applied = !by_root.is_empty() || !file_changes.is_empty();
+
+	let mut db = db.lock();
+	for path in file_changes {
+		tracing::info!("File {:?} of a binary changed", path);
+		let revision = binary_file_revision(&path);
+		db.file_update(path, revision);
+	}
+
+	for ((root, schema), changes) in by_root {
+		tracing::info!("Applying {} changes to {:?}", changes.len(), root);
+		root_files_apply(&mut db, schema, &root, &changes);
+	}
+
+	applied
+}
@@ -1,10 +1,13 @@
 use std::collections::BTreeMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use serde::Serialize;
+use thiserror::Error;
 
-use crate::{Cause, Db, Executable, Outcome, RuntimeTask, Schema};
+use crate::{
+	bytes_hash, Cause, Db, Executable, ExecutableKind, File, Outcome, RuntimeTask, Schema,
+};
 
 #[salsa::input]
 #[derive(Debug, Clone)]
@@ -23,36 +26,224 @@ pub struct BinaryNative {
 	pub executable: String,
 	pub env: BTreeMap<String, String>,
 	pub args: Vec<String>,
+	/// Working directory, the schema directory when not set
+	pub cwd: Option<PathBuf>,
+	/// Files with `KEY=value` lines, loaded before `env`. Their revisions are kept up to
+	/// date by the watcher, so edits resolve the binary again.
+	pub env_files: Vec<File>,
+	/// Whether the process sees the environment of the daemon
+	pub inherit_env: bool,
 }
 
 #[derive(Serialize, Clone, Debug, PartialEq, Eq)]
 pub enum BinaryRuntimeResolved {
 	Native {
 		executable: PathBuf,
+		/// The executable registered as a file with its content revision, the watcher keeps
+		/// it up to date, so transforms run by a replaced binary are run again
+		#[serde(skip)]
+		executable_file: File,
+		/// Variables from the env files and the binary config, with references resolved
 		env: BTreeMap<String, String>,
 		args: Vec<String>,
+		cwd: PathBuf,
+		inherit_env: bool,
 	},
 }
 
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum InterpolationError {
+	#[error("Variable {0:?} is not defined")]
+	Undefined(String),
+	#[error("Unterminated variable reference in {0:?}")]
+	Unterminated(String),
+}
+
+/// Replaces `${NAME}` and `${NAME:-default}` references, `$$` is a literal `$`
+pub fn interpolate(
+	value: &str,
+	lookup: impl Fn(&str) -> Option<String>,
+) -> Result<String, InterpolationError> {
+	let mut result = String::with_capacity(value.len());
+	let mut rest = value;
+
+	while let Some(i) = rest.find('$') {
+		result.push_str(&rest[..i]);
+		let tail = &rest[i + 1..];
+
+		if let Some(tail) = tail.strip_prefix('$') {
+			result.push('$');
+			rest = tail;
+			continue;
+		}
+
+		let Some(tail) = tail.strip_prefix('{') else {
+			result.push('$');
+			rest = tail;
+			continue;
+		};
+
+		let Some(end) = tail.find('}') else {
+			return Err(InterpolationError::Unterminated(value.to_owned()));
+		};
+
+		let reference = &tail[..end];
+		let (name, default) = match reference.split_once(":-") {
+			Some((name, default)) => (name, Some(default)),
+			None => (reference, None),
+		};
+
+		match lookup(name).or_else(|| default.map(str::to_owned)) {
+			Some(v) => result.push_str(&v),
+			None => return Err(InterpolationError::Undefined(name.to_owned())),
+		}
+
+		rest = &tail[end + 1..];
+	}
+
+	result.push_str(rest);
+	Ok(result)
+}
+
+/// Parses `KEY=value` lines of a `.env` file, skipping comments and blank lines
+pub fn parse_env_file(content: &str) -> Vec<(String, String)> {
+	content
+		.lines()
+		.filter_map(|line| {
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				return None;
+			}
+
+			let line = line.strip_prefix("export ").unwrap_or(line);
+			let (key, value) = line.split_once('=')?;
+			let value = value.trim();
+
+			let value = match value.chars().next() {
+				Some(q @ ('"' | '\'')) if value.len() >= 2 && value.ends_with(q) => {
+					value[1..value.len() - 1].to_owned()
+				}
+				_ => value.split(" #").next().unwrap_or_default().trim_end().to_owned(),
+			};
+
+			Some((key.trim().to_owned(), value))
+		})
+		.collect()
+}
+
+/// Revision of a file a binary depends on, its env files or its executable. Missing files
+/// have none and are reported by the resolution
+pub fn binary_file_revision(path: &Path) -> u64 {
+	std::fs::read(path).map_or(0, |content| bytes_hash(&content))
+}
+
 #[salsa::tracked]
 pub fn binary_resolve(
 	db: &dyn Db,
 	schema: Schema,
 	binary: Binary,
 ) -> Outcome<BinaryRuntimeResolved> {
-	RuntimeTask::push(db, Arc::new(BinaryResolve { schema, binary }));
+	let BinaryRuntime::Native(native) = binary.runtime(db);
+	let revisions = native.env_files.iter().map(|f| f.revision(db)).collect();
+	binary_resolve_revisions(db, schema, binary, revisions)
+}
+
+/// Resolutions are stored per revisions of the env files
+#[salsa::tracked]
+pub fn binary_resolve_revisions(
+	db: &dyn Db,
+	schema: Schema,
+	binary: Binary,
+	revisions: Vec<u64>,
+) -> Outcome<BinaryRuntimeResolved> {
+	RuntimeTask::push(
+		db,
+		Arc::new(BinaryResolve {
+			schema,
+			binary,
+			revisions,
+		}),
+	);
 	Outcome::Err(Cause::Pending)
 }
 
 pub struct BinaryResolve {
 	pub schema: Schema,
 	pub binary: Binary,
+	pub revisions: Vec<u64>,
 }
 
-impl Executable for BinaryResolve {}
+impl Executable for BinaryResolve {
+	fn schema(&self) -> Schema {
+		self.schema
+	}
+
+	fn kind(&self) -> ExecutableKind {
+		ExecutableKind::ResolveBinary
+	}
+
+	fn name(&self, db: &dyn Db) -> String {
+		self.binary.name(db)
+	}
+}
 
 impl BinaryResolve {
 	pub fn resolve(&self, db: &mut dyn Db, object: Outcome<BinaryRuntimeResolved>) {
-		binary_resolve::set(db, self.schema, self.binary, object)
+		let revisions = self.revisions.clone();
+		binary_resolve_revisions::set(db, self.schema, self.binary, revisions, object)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn lookup(name: &str) -> Option<String> {
+		match name {
+			"HOME" => Some("/home/kabina".into()),
+			"api.port" => Some("8080".into()),
+			_ => None,
+		}
+	}
+
+	#[test]
+	fn test_interpolate() {
+		assert_eq!(interpolate("${HOME}/bin", lookup).unwrap(), "/home/kabina/bin");
+		assert_eq!(
+			interpolate("http://localhost:${api.port}/", lookup).unwrap(),
+			"http://localhost:8080/"
+		);
+		assert_eq!(interpolate("${MISSING:-3000}", lookup).unwrap(), "3000");
+		assert_eq!(interpolate("$$HOME $HOME", lookup).unwrap(), "$HOME $HOME");
+		assert_eq!(
+			interpolate("${MISSING}", lookup),
+			Err(InterpolationError::Undefined("MISSING".into()))
+		);
+		assert_eq!(
+			interpolate("${HOME", lookup),
+			Err(InterpolationError::Unterminated("${HOME".into()))
+		);
+	}
+
+	#[test]
+	fn test_parse_env_file() {
+		let content = r#"
+# Database
+export DATABASE_URL="postgres://localhost/kabina"
+LOG_LEVEL=debug # verbose
+EMPTY=
+QUOTED='single # not a comment'
+invalid line
+"#;
+
+		assert_eq!(
+			parse_env_file(content),
+			vec![
+				("DATABASE_URL".into(), "postgres://localhost/kabina".into()),
+				("LOG_LEVEL".into(), "debug".into()),
+				("EMPTY".into(), "".into()),
+				("QUOTED".into(), "single # not a comment".into()),
+			]
+		);
 	}
 }
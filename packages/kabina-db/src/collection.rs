@@ -2,7 +2,10 @@ use std::collections::BTreeMap;
 use std::path::PathBuf;
 
 use crate::deps::Input;
-use crate::{file_group_files, transform_files, Cause, Db, File, Outcome, Schema};
+use crate::{
+	file_group_files, transform_files, Cause, Db, DiagnosticSeverity, DiagnosticSource,
+	Diagnostics, File, Outcome, Schema, Span,
+};
 
 #[derive(Debug, Clone)]
 pub struct CollectionItem {
@@ -15,6 +18,8 @@ pub struct CollectionItem {
 pub struct Collection {
 	pub name: String,
 	pub items: Vec<CollectionItem>,
+	/// Declaration in the schema module, diagnostics point at it
+	pub span: Option<Span>,
 }
 
 #[salsa::tracked]
@@ -22,17 +27,44 @@ pub fn collection_files(
 	db: &dyn Db,
 	schema: Schema,
 	collection: Collection,
+) -> Outcome<BTreeMap<PathBuf, File>> {
+	collection_files_collect(db, schema, collection)
+		.map_err(|e| e.context(format!("Collection {:?}", collection.name(db))))
+}
+
+fn collection_files_collect(
+	db: &dyn Db,
+	schema: Schema,
+	collection: Collection,
 ) -> Outcome<BTreeMap<PathBuf, File>> {
 	let mut pending = false;
 	let mut buffer = BTreeMap::new();
 
+	// Items listed later replace the files of the items before them
+	let mut insert = |path: PathBuf, file: File| {
+		let replaced = buffer.insert(path.clone(), file);
+		if replaced.map_or(false, |f| f != file) {
+			let source = DiagnosticSource::Collection(collection);
+			let message = format!("Several items provide {:?}, the last one is used", path);
+			Diagnostics::report(db, DiagnosticSeverity::Warning, source, message);
+		}
+	};
+
 	for item in collection.items(db) {
+		let root = item.content.root(db, schema);
+		let relative = |file: File| -> PathBuf {
+			let path = file.path(db);
+			match path.strip_prefix(&root) {
+				Ok(p) => item.prefix.join(p),
+				Err(_) => item.prefix.join(path.file_name().unwrap_or_default()),
+			}
+		};
+
 		match item.content {
 			Input::FileGroup(g) => match file_group_files(db, schema, g) {
 				Ok(files) => {
 					for file in files {
-						let path = item.prefix.join(file.path(db));
-						buffer.insert(path, file);
+						insert(relative(file), file);
 					}
 				}
 				Err(Cause::Pending) => pending = true,
@@ -41,8 +73,7 @@ pub fn collection_files(
 			Input::Transform(t) => match transform_files(db, schema, t) {
 				Ok(files) => {
 					for file in files {
-						let path = item.prefix.join(file.path(db));
-						buffer.insert(path, file);
+						insert(relative(file), file);
 					}
 				}
 				Err(Cause::Pending) => pending = true,
@@ -1,21 +1,24 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 #[salsa::jar(db = Db)]
 pub struct Jar(
 	Project,
 	crate::fileset::FileGroup,
-	Diagnostic,
+	crate::diagnostic::Diagnostics,
 	Schema,
 	crate::collection::Collection,
 	crate::collection::collection_files,
 	crate::server::Server,
 	crate::service::Service,
+	crate::service::service_inputs_revision,
 	crate::transform::Transform,
 	crate::transform::transform_inputs,
 	crate::transform::transform_files,
 	crate::transform::transform_result_for_file,
+	crate::transform::transform_result_for_revision,
 	crate::transform::transform_dependencies,
+	crate::transform::transform_output_dir,
 	crate::fileset::RuntimeTask,
 	crate::fileset::File,
 	crate::fileset::roots,
@@ -23,14 +26,21 @@ pub struct Jar(
 	crate::fileset::file_group_matcher,
 	crate::fileset::root_files,
 	crate::fileset::root_file_groups,
-	crate::fileset::file_group_resolved_paths,
+	crate::fileset::file_group_resolved_files,
 	crate::fileset::file_group_files,
 	crate::binary::Binary,
 	// crate::toolchain::ToolchainObject,
 	crate::binary::binary_resolve,
+	crate::binary::binary_resolve_revisions,
 );
 
-pub trait Db: salsa::DbWithJar<Jar> {}
+pub trait Db: salsa::DbWithJar<Jar> {
+	fn sqlite(&self) -> &Arc<Mutex<Connection>>;
+	/// Database of the project the schema belongs to, for file hashes and transform outputs
+	fn sqlite_project(&self, schema: Schema) -> Arc<Mutex<Connection>>;
+	/// Content-addressed store for transform outputs, if the database has one
+	fn store(&self) -> Option<&ContentStore>;
+}
 
 use dashmap::DashMap;
 use parking_lot::Mutex;
@@ -39,33 +49,117 @@ pub use salsa::debug::DebugWithDb;
 pub use salsa::AsId;
 use url::Url;
 
-use crate::sqlite::sqlite_schema_add;
-use crate::Schema;
+use crate::sqlite::{sqlite_schema_add, sqlite_schema_all, sqlite_schema_remove};
+use crate::store::ContentStore;
+use crate::{File, Schema, KABINA_DIR};
 
 #[salsa::db(Jar)]
 pub struct Database {
 	sqlite: Arc<Mutex<Connection>>,
+	/// Databases of the projects by schema, projects that are not opened use `sqlite`
+	projects: Arc<DashMap<Url, Arc<Mutex<Connection>>>>,
 	schemas: DashMap<Url, Schema>,
+	files: Arc<DashMap<PathBuf, File>>,
+	store: Option<Arc<ContentStore>>,
 	storage: salsa::Storage<Self>,
 }
 
 impl Database {
 	pub fn new() -> Self {
 		let sqlite = crate::sqlite::sqlite_setup().unwrap();
+		Self::with_connection(sqlite)
+	}
+
+	/// Database that keeps file hashes, transform outputs and schemas on disk
+	pub fn open(path: &Path) -> Result<Self, anyhow::Error> {
+		let sqlite = crate::sqlite::sqlite_open(path)?;
+		Ok(Self::with_connection(sqlite))
+	}
+
+	fn with_connection(sqlite: Connection) -> Self {
 		let storage = Default::default();
 		Self {
 			storage,
+			projects: Default::default(),
 			schemas: DashMap::default(),
+			files: Default::default(),
+			store: None,
 			sqlite: Arc::new(Mutex::new(sqlite)),
 		}
 	}
 
+	/// Keeps the file hashes and transform outputs of the schema in its project database
+	pub fn project_open(&self, url: &Url) -> Result<(), anyhow::Error> {
+		if self.projects.contains_key(url) {
+			return Ok(());
+		}
+
+		let sqlite = crate::sqlite::sqlite_open(&project_database_path(url))?;
+		self.projects
+			.insert(url.clone(), Arc::new(Mutex::new(sqlite)));
+		Ok(())
+	}
+
+	/// Keeps transform outputs in a content-addressed store under `root`
+	pub fn with_store(mut self, root: PathBuf) -> Self {
+		self.store = Some(Arc::new(ContentStore::new(root)));
+		self
+	}
+
+	/// Returns the file registered for the path, updating its revision if it changed
+	pub fn file_update(&mut self, path: PathBuf, revision: u64) -> File {
+		match self.files.get(&path).map(|f| *f) {
+			Some(file) => {
+				if file.revision(self) != revision {
+					file.set_revision(self).to(revision);
+				}
+				file
+			}
+			None => {
+				let file = File::new(self, path.clone(), revision);
+				self.files.insert(path, file);
+				file
+			}
+		}
+	}
+
+	/// Registry of files by path, so every path is represented by a single input. Queries
+	/// do not see it, files are registered by the tasks and the watcher.
+	pub(crate) fn files(&self) -> &DashMap<PathBuf, File> {
+		&self.files
+	}
+
 	pub fn schema_add(&self, url: Url, schema: Schema) -> Result<(), anyhow::Error> {
 		let c = self.sqlite.lock();
 		sqlite_schema_add(&c, &url)?;
 		self.schemas.insert(url, schema);
 		Ok(())
 	}
+
+	/// Schemas loaded by the running daemon
+	pub fn schema_loaded(&self) -> Vec<(Url, Schema)> {
+		let mut schemas = self
+			.schemas
+			.iter()
+			.map(|s| (s.key().clone(), *s.value()))
+			.collect::<Vec<_>>();
+
+		schemas.sort_by(|a, b| a.0.cmp(&b.0));
+		schemas
+	}
+
+	/// Schemas registered by previous runs
+	pub fn schema_stored(&self) -> Result<Vec<Url>, anyhow::Error> {
+		let c = self.sqlite.lock();
+		Ok(sqlite_schema_all(&c)?.into_iter().map(|s| s.url).collect())
+	}
+
+	pub fn schema_forget(&self, url: &Url) -> Result<(), anyhow::Error> {
+		let c = self.sqlite.lock();
+		sqlite_schema_remove(&c, url)?;
+		self.schemas.remove(url);
+		Ok(())
+	}
 }
 
 #[salsa::input]
@@ -73,7 +167,22 @@ pub struct Project {
 	root: PathBuf,
 }
 
-impl Db for Database {}
+impl Db for Database {
+	fn sqlite(&self) -> &Arc<Mutex<Connection>> {
+		&self.sqlite
+	}
+
+	fn sqlite_project(&self, schema: Schema) -> Arc<Mutex<Connection>> {
+		match self.projects.get(&schema.url(self)) {
+			Some(project) => project.clone(),
+			None => self.sqlite.clone(),
+		}
+	}
+
+	fn store(&self) -> Option<&ContentStore> {
+		self.store.as_deref()
+	}
+}
 // ANCHOR_END: db
 
 impl salsa::Database for Database {}
@@ -82,15 +191,20 @@ impl salsa::ParallelDatabase for Database {
 	fn snapshot(&self) -> salsa::Snapshot<Self> {
 		salsa::Snapshot::new(Database {
 			sqlite: self.sqlite.clone(),
+			projects: self.projects.clone(),
 			storage: self.storage.snapshot(),
 			schemas: DashMap::new(),
+			files: self.files.clone(),
+			store: self.store.clone(),
 		})
 	}
 }
 
-#[salsa::accumulator]
-struct Diagnostic(String);
-
-impl Diagnostic {}
-
 pub type SharedDatabase = Arc<Mutex<Database>>;
+
+/// Each project keeps its own database next to the schema, shared by the daemon and builds
+pub fn project_database_path(schema: &Url) -> PathBuf {
+	let module = PathBuf::from(schema.path());
+	let root = module.parent().map(Path::to_owned).unwrap_or_default();
+	root.join(KABINA_DIR).join("kabina.db")
+}
@@ -1,10 +1,13 @@
 use std::collections::BTreeMap;
+use std::path::PathBuf;
 
 use salsa::AsId;
 use serde::Serialize;
 use serde_json::{Map, Value};
 
-use crate::{Binary, BinaryRuntimeResolved, FileGroup, Transform};
+use crate::{
+	transform_output_dir, Binary, BinaryRuntimeResolved, Db, FileGroup, Schema, Transform,
+};
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Input {
@@ -12,6 +15,16 @@ pub enum Input {
 	Transform(Transform),
 }
 
+impl Input {
+	/// Directory the paths of this input's files are relative to
+	pub fn root(self, db: &dyn Db, schema: Schema) -> PathBuf {
+		match self {
+			Input::FileGroup(g) => g.root(db),
+			Input::Transform(t) => transform_output_dir(db, schema, t),
+		}
+	}
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Dependency {
 	FileGroup(FileGroup),
@@ -0,0 +1,84 @@
+use std::fmt;
+
+use url::Url;
+
+use crate::{Collection, Db, FileGroup, Transform};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DiagnosticSeverity {
+	Warning,
+	Error,
+}
+
+/// Object of the schema a diagnostic is about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DiagnosticSource {
+	FileGroup(FileGroup),
+	Transform(Transform),
+	Collection(Collection),
+}
+
+impl DiagnosticSource {
+	/// Kind and name of the object, as the schema module declares it
+	pub fn describe(self, db: &dyn Db) -> String {
+		match self {
+			DiagnosticSource::FileGroup(g) => format!("file group {:?}", g.name(db)),
+			DiagnosticSource::Transform(t) => format!("transform {:?}", t.name(db)),
+			DiagnosticSource::Collection(c) => format!("collection {:?}", c.name(db)),
+		}
+	}
+
+	/// Position of the object declaration in the schema module
+	pub fn span(self, db: &dyn Db) -> Option<Span> {
+		match self {
+			DiagnosticSource::FileGroup(g) => g.span(db),
+			DiagnosticSource::Transform(t) => t.span(db),
+			DiagnosticSource::Collection(c) => c.span(db),
+		}
+	}
+}
+
+/// Position of the call that declared an object in the schema module
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+	pub module: Url,
+	pub line: u32,
+	pub column: u32,
+}
+
+impl fmt::Display for Span {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}:{}:{}", self.module.path(), self.line, self.column)
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiagnosticMessage {
+	pub severity: DiagnosticSeverity,
+	pub message: String,
+	pub source: DiagnosticSource,
+	pub span: Option<Span>,
+}
+
+/// Problems of the schema found by queries, collected by whoever drives them
+#[salsa::accumulator]
+pub struct Diagnostics(DiagnosticMessage);
+
+impl Diagnostics {
+	pub fn report(
+		db: &dyn Db,
+		severity: DiagnosticSeverity,
+		source: DiagnosticSource,
+		message: impl Into<String>,
+	) {
+		let message = DiagnosticMessage {
+			severity,
+			message: message.into(),
+			source,
+			span: source.span(db),
+		};
+
+		tracing::info!("Diagnostic {:?}", message);
+		Diagnostics::push(db, message);
+	}
+}
@@ -1,3 +1,4 @@
+use std::fmt::{Display, Formatter};
 use std::sync::Arc;
 
 use by_address::ByAddress;
@@ -15,6 +16,40 @@ impl Cause {
 	pub fn from_err(e: impl std::error::Error + Send + Sync + 'static) -> Cause {
 		Cause::Error(Arc::new(ByAddress(e.into())))
 	}
+
+	pub fn from_anyhow(e: anyhow::Error) -> Cause {
+		Cause::Error(Arc::new(ByAddress(e)))
+	}
+
+	/// Describes the node that failed on top of the error, pending stays pending
+	pub fn context(self, context: impl Display) -> Cause {
+		match self {
+			Cause::Pending => Cause::Pending,
+			Cause::Error(source) => Cause::from_err(ContextError {
+				context: context.to_string(),
+				source,
+			}),
+		}
+	}
+}
+
+/// Error shared between queries with a description of the node it went through
+#[derive(Debug)]
+struct ContextError {
+	context: String,
+	source: Arc<ByAddress<anyhow::Error>>,
+}
+
+impl Display for ContextError {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		f.write_str(&self.context)
+	}
+}
+
+impl std::error::Error for ContextError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		Some(&*self.source.0)
+	}
 }
 
 pub type Outcome<T> = std::result::Result<T, Cause>;
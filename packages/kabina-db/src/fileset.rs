@@ -4,14 +4,22 @@ use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::UNIX_EPOCH;
 
+use anyhow::anyhow;
 use by_address::ByAddress;
 use downcast_rs::DowncastSync;
 use globset::{Candidate, GlobSet};
+use parking_lot::Mutex;
+use rusqlite::Connection;
+use sha2::{Digest, Sha256};
 
 use super::db::Db;
-use crate::{Cause, Database, Outcome, Schema};
+use crate::sqlite::{sqlite_file_hash_get, sqlite_file_hash_put};
+use crate::{
+	Cause, Database, DiagnosticSeverity, DiagnosticSource, Diagnostics, Outcome, Schema, Span,
+	KABINA_DIR,
+};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FileGroupStategy {
 	Hash,
 	Time,
@@ -31,6 +39,10 @@ pub struct FileGroup {
 	pub root: PathBuf,
 	#[return_ref]
 	pub items: Vec<FileGroupItem>,
+	/// Honour `.gitignore` and `.ignore` files while walking the root
+	pub ignore_files: bool,
+	/// Declaration in the schema module, diagnostics point at it
+	pub span: Option<Span>,
 }
 
 #[derive(Default, Debug, PartialEq, Eq)]
@@ -39,6 +51,10 @@ pub struct SchemaRoots {
 }
 
 impl SchemaRoots {
+	pub fn paths(&self) -> impl Iterator<Item = &PathBuf> {
+		self.roots.keys()
+	}
+
 	pub fn add(&mut self, db: &dyn Db, file_group: FileGroup) {
 		let root = file_group.root(db);
 
@@ -88,14 +104,58 @@ pub fn roots(db: &dyn Db, schema: Schema) -> Arc<SchemaRoots> {
 }
 
 #[salsa::tracked]
-pub fn file_group_root(db: &dyn Db, schema: Schema, group: FileGroup) -> PathBuf {
+pub fn file_group_root(db: &dyn Db, schema: Schema, group: FileGroup) -> Outcome<PathBuf> {
 	let roots = roots(db, schema);
 	roots
 		.roots
 		.iter()
 		.find(|r| r.1.contains_key(&group))
 		.map(|(k, _)| k.clone())
-		.unwrap()
+		.ok_or_else(|| {
+			let name = group.name(db);
+			Cause::from_anyhow(anyhow!("File group {:?} is not part of the schema", name))
+		})
+}
+
+/// Compiled patterns of a file group, relative to the root the group is walked from.
+/// Items starting with `!` exclude paths, excluding a directory excludes all its content.
+#[derive(Debug)]
+pub struct FileGroupMatcher {
+	include: GlobSet,
+	/// Strategy of each pattern in `include`, in the same order
+	strategies: Vec<FileGroupStategy>,
+	exclude: GlobSet,
+	pub ignore_files: bool,
+}
+
+impl FileGroupMatcher {
+	pub fn is_match(&self, path: &Path) -> bool {
+		self.include.is_match(path) && !self.is_excluded(path)
+	}
+
+	/// Checks the path and all its parent directories against the exclude patterns
+	pub fn is_excluded(&self, path: &Path) -> bool {
+		path.ancestors()
+			.filter(|p| !p.as_os_str().is_empty())
+			.any(|p| self.exclude.is_match(p))
+	}
+
+	/// Versioning strategy of the first item that matches the path
+	pub fn strategy(&self, path: &Path) -> FileGroupStategy {
+		let candidate = Candidate::new(path);
+		self.include
+			.matches_candidate(&candidate)
+			.into_iter()
+			.min()
+			.map(|i| self.strategies[i])
+			.unwrap_or(FileGroupStategy::Time)
+	}
+}
+
+/// Checks the glob of an item pattern, `!` marks exclusion and is not part of the glob
+pub fn file_group_pattern_validate(pattern: &str) -> Result<(), globset::Error> {
+	let pattern = pattern.strip_prefix('!').unwrap_or(pattern);
+	globset::Glob::new(pattern).map(drop)
 }
 
 #[salsa::tracked]
@@ -103,12 +163,19 @@ pub fn file_group_matcher(
 	db: &dyn Db,
 	group: FileGroup,
 	prefix: PathBuf,
-) -> ByAddress<Arc<GlobSet>> {
-	let mut matcher = globset::GlobSetBuilder::new();
+) -> Outcome<ByAddress<Arc<FileGroupMatcher>>> {
+	let mut include = globset::GlobSetBuilder::new();
+	let mut exclude = globset::GlobSetBuilder::new();
+	let mut strategies = Vec::new();
 
 	let items = group.items(db);
 	for item in items {
-		let mut glob_str = Cow::Borrowed(&item.pattern);
+		let (negated, pattern) = match item.pattern.strip_prefix('!') {
+			Some(pattern) => (true, pattern),
+			None => (false, item.pattern.as_str()),
+		};
+
+		let mut glob_str = Cow::Borrowed(pattern);
 		let prefix = prefix.to_string_lossy();
 		if prefix != "" {
 			let mut p = prefix.as_ref();
@@ -120,12 +187,29 @@ pub fn file_group_matcher(
 
 		tracing::info!("Glob: {:?}", glob_str);
 
-		let glob = globset::Glob::new(&glob_str).unwrap();
+		let glob = match globset::Glob::new(&glob_str) {
+			Ok(glob) => glob,
+			Err(e) => {
+				let source = DiagnosticSource::FileGroup(group);
+				Diagnostics::report(db, DiagnosticSeverity::Error, source, e.to_string());
+				return Err(Cause::from_err(e));
+			}
+		};
 
-		matcher.add(glob);
+		if negated {
+			exclude.add(glob);
+		} else {
+			include.add(glob);
+			strategies.push(item.strategy);
+		}
 	}
 
-	ByAddress(Arc::new(matcher.build().unwrap()))
+	Ok(ByAddress(Arc::new(FileGroupMatcher {
+		include: include.build().map_err(Cause::from_err)?,
+		strategies,
+		exclude: exclude.build().map_err(Cause::from_err)?,
+		ignore_files: group.ignore_files(db),
+	})))
 }
 
 #[salsa::tracked]
@@ -133,17 +217,35 @@ pub fn root_file_groups(
 	db: &dyn Db,
 	schema: Schema,
 	path: PathBuf,
-) -> BTreeMap<FileGroup, PathBuf> {
+) -> Outcome<BTreeMap<FileGroup, PathBuf>> {
 	let roots = roots(db, schema);
 
 	tracing::info!("Getting groups for root {:?}", path);
 
-	let root = roots.roots.get(&path).unwrap();
+	roots
+		.roots
+		.get(&path)
+		.cloned()
+		.ok_or_else(|| Cause::from_anyhow(anyhow!("Root {:?} is not part of the schema", path)))
+}
 
-	root.clone()
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecutableKind {
+	WalkFiles,
+	Transform,
+	ResolveBinary,
 }
 
-pub trait Executable: DowncastSync {}
+/// Work a query needs from the outside world, executed by whoever drives the query
+pub trait Executable: DowncastSync {
+	/// Schema the work is done for, its clients are notified about the progress
+	fn schema(&self) -> Schema;
+
+	fn kind(&self) -> ExecutableKind;
+
+	/// What the task works on, like the walked root or the transformed file
+	fn name(&self, db: &dyn Db) -> String;
+}
 downcast_rs::impl_downcast!(sync Executable);
 
 #[salsa::accumulator]
@@ -152,60 +254,204 @@ pub struct RuntimeTask(Arc<dyn Executable>);
 pub struct ResolveRootFiles {
 	pub schema: Schema,
 	pub root: PathBuf,
-	pub matchers: BTreeMap<FileGroup, ByAddress<Arc<GlobSet>>>,
+	pub matchers: BTreeMap<FileGroup, ByAddress<Arc<FileGroupMatcher>>>,
+}
+
+impl Executable for ResolveRootFiles {
+	fn schema(&self) -> Schema {
+		self.schema
+	}
+
+	fn kind(&self) -> ExecutableKind {
+		ExecutableKind::WalkFiles
+	}
+
+	fn name(&self, _db: &dyn Db) -> String {
+		self.root.display().to_string()
+	}
 }
 
-impl Executable for ResolveRootFiles {}
+/// Paths found by a walk with their revisions, by file group
+pub type WalkedFiles = BTreeMap<FileGroup, Vec<(PathBuf, u64)>>;
 
 impl ResolveRootFiles {
-	pub fn resolve(&self, db: &mut Database) {
-		let mut results: BTreeMap<FileGroup, Vec<PathBuf>> = BTreeMap::new();
+	/// Walks the root and returns the number of files visited
+	pub fn resolve(&self, db: &mut Database) -> usize {
+		let sqlite = db.sqlite_project(self.schema);
+		let (files, visited) = self.walk(&sqlite);
+		self.apply(db, files);
+		visited
+	}
+
+	/// Walks the root and reads the revisions without the database, returns the files of
+	/// each group and the number of files visited
+	pub fn walk(&self, sqlite: &Mutex<Connection>) -> (WalkedFiles, usize) {
+		let mut visited = 0;
+		let mut results: WalkedFiles = BTreeMap::new();
 
 		for group in self.matchers.keys() {
 			// Fill with default values for each group
 			results.insert(*group, Vec::new());
 		}
 
-		for _entry in walkdir::WalkDir::new(&self.root)
-			.into_iter()
-			.filter_entry(|e| {
-				if e.file_type().is_dir() {
-					// TODO: blacklist
-					return true;
-				}
-
-				tracing::info!("Visiting {:?}", e.path());
+		for (ignore_files, matchers) in partition_matchers(&self.matchers) {
+			for path in walk_root(&self.root, &self.root, ignore_files, &matchers) {
+				tracing::info!("Visiting {:?}", path);
+				visited += 1;
 
-				let candidate = Candidate::new(e.path().strip_prefix(&self.root).unwrap());
+				let relative = path.strip_prefix(&self.root).unwrap();
 
-				for (group, matcher) in &self.matchers {
-					if !matcher.matches_candidate(&candidate).is_empty() {
-						tracing::info!("Matched group {:?}", group);
-						results.get_mut(group).unwrap().push(e.path().to_owned())
+				for (group, matcher) in &matchers {
+					if !matcher.is_match(relative) {
+						continue;
 					}
+
+					tracing::info!("Matched group {:?}", group);
+
+					// The file may be removed between the walk and the read
+					let revision = match file_revision(sqlite, &path, matcher.strategy(relative)) {
+						Ok(revision) => revision,
+						Err(e) => {
+							tracing::warn!("Failed to read {:?}: {}", path, e);
+							continue;
+						}
+					};
+
+					results
+						.get_mut(group)
+						.unwrap()
+						.push((path.clone(), revision))
 				}
+			}
+		}
 
-				true
+		(results, visited)
+	}
+
+	/// Registers the files found by `walk` and stores them as the files of the root
+	pub fn apply(&self, db: &mut Database, files: WalkedFiles) {
+		let files = files
+			.into_iter()
+			.map(|(group, paths)| {
+				let files = paths
+					.into_iter()
+					.map(|(path, revision)| db.file_update(path, revision))
+					.collect();
+				(group, files)
 			})
-			.filter_map(|e| Some(e))
-		{}
+			.collect();
+
+		root_files::set(db, self.schema, self.root.clone(), Result::Ok(files));
+	}
 
-		root_files::set(db, self.schema, self.root.clone(), Result::Ok(results))
+	/// Stores the error of a walk that did not complete, the file groups of the root report it
+	pub fn fail(&self, db: &mut Database, cause: Cause) {
+		root_files::set(db, self.schema, self.root.clone(), Err(cause));
 	}
 }
 
+type Matchers = BTreeMap<FileGroup, ByAddress<Arc<FileGroupMatcher>>>;
+
+/// Groups that honour ignore files and groups that do not need separate walks
+fn partition_matchers(matchers: &Matchers) -> Vec<(bool, Matchers)> {
+	let (ignoring, all): (Matchers, Matchers) = matchers
+		.iter()
+		.map(|(g, m)| (*g, m.clone()))
+		.partition(|(_, m)| m.ignore_files);
+
+	[(true, ignoring), (false, all)]
+		.into_iter()
+		.filter(|(_, m)| !m.is_empty())
+		.collect()
+}
+
+/// Walks `start` inside of `root` and returns the files. Directories are pruned
+/// before descending when every group excludes them.
+fn walk_root(root: &Path, start: &Path, ignore_files: bool, matchers: &Matchers) -> Vec<PathBuf> {
+	let root = root.to_owned();
+	let prune = matchers.values().cloned().collect::<Vec<_>>();
+
+	let mut builder = ignore::WalkBuilder::new(start);
+	builder
+		.standard_filters(false)
+		.git_ignore(ignore_files)
+		.git_exclude(ignore_files)
+		.ignore(ignore_files)
+		.parents(ignore_files)
+		.require_git(false)
+		.filter_entry(move |e| {
+			if !e.file_type().map_or(false, |t| t.is_dir()) {
+				return true;
+			}
+
+			// Never descend into our own outputs
+			if e.file_name() == KABINA_DIR || (ignore_files && e.file_name() == ".git") {
+				return false;
+			}
+
+			match e.path().strip_prefix(&root) {
+				Ok(relative) if !relative.as_os_str().is_empty() => {
+					!prune.iter().all(|m| m.is_excluded(relative))
+				}
+				_ => true,
+			}
+		});
+
+	builder
+		.build()
+		.filter_map(|e| match e {
+			Ok(e) => Some(e),
+			Err(e) => {
+				tracing::warn!("Failed to walk: {}", e);
+				None
+			}
+		})
+		.filter(|e| !e.file_type().map_or(false, |t| t.is_dir()))
+		.map(|e| e.into_path())
+		.collect()
+}
+
+/// Checks a single path against ignore files in its parent directories up to the root
+fn is_ignored_by_files(root: &Path, path: &Path) -> bool {
+	let is_dir = path.is_dir();
+	let mut dir = path.parent();
+
+	while let Some(d) = dir {
+		if !d.starts_with(root) {
+			break;
+		}
+
+		let mut builder = ignore::gitignore::GitignoreBuilder::new(d);
+		builder.add(d.join(".gitignore"));
+		builder.add(d.join(".ignore"));
+
+		if let Ok(gitignore) = builder.build() {
+			if gitignore
+				.matched_path_or_any_parents(path, is_dir)
+				.is_ignore()
+			{
+				return true;
+			}
+		}
+
+		dir = d.parent();
+	}
+
+	false
+}
+
 #[salsa::tracked]
 pub fn root_files(
 	db: &dyn Db,
 	schema: Schema,
 	root: PathBuf,
-) -> Outcome<BTreeMap<FileGroup, Vec<PathBuf>>> {
-	let groups = root_file_groups(db, schema, root.clone());
+) -> Outcome<BTreeMap<FileGroup, Vec<File>>> {
+	let groups = root_file_groups(db, schema, root.clone())?;
 
 	let matchers = groups
 		.iter()
-		.map(|(g, prefix)| (*g, file_group_matcher(db, *g, prefix.clone())))
-		.collect::<BTreeMap<_, _>>();
+		.map(|(g, prefix)| Ok((*g, file_group_matcher(db, *g, prefix.clone())?)))
+		.collect::<Outcome<BTreeMap<_, _>>>()?;
 
 	let task = Arc::new(ResolveRootFiles {
 		root,
@@ -219,24 +465,23 @@ pub fn root_files(
 }
 
 #[salsa::tracked]
-pub fn file_group_resolved_paths(
+pub fn file_group_resolved_files(
 	db: &dyn Db,
 	schema: Schema,
 	group: FileGroup,
-) -> Outcome<Vec<PathBuf>> {
-	let root = file_group_root(db, schema, group);
+) -> Outcome<Vec<File>> {
+	let root = file_group_root(db, schema, group)?;
 
 	tracing::info!("Gettings files for {:?}, root: {:?}", group, root);
-	let root = root_files(db, schema, root)?;
+	let files = root_files(db, schema, root.clone())?;
 
-	tracing::info!("Root files {:?}", root);
+	tracing::info!("Root files {:?}", files);
 
-	let paths = root
-		.get(&group)
-		.cloned()
-		.expect("Root does not have this group");
+	let files = files.get(&group).cloned().ok_or_else(|| {
+		Cause::from_anyhow(anyhow!("Root {:?} does not have the file group", root))
+	})?;
 
-	Outcome::Ok(paths)
+	Outcome::Ok(files)
 }
 
 #[salsa::input]
@@ -246,25 +491,206 @@ pub struct File {
 	pub revision: u64,
 }
 
-pub fn file_modified_time_in_seconds(path: &Path) -> u64 {
-	std::fs::metadata(path)
-		.unwrap()
-		.modified()
-		.unwrap()
+/// Fails when the file is gone, it may be removed between the walk and the read
+pub fn file_modified_time_in_seconds(path: &Path) -> std::io::Result<u64> {
+	Ok(std::fs::metadata(path)?
+		.modified()?
 		.duration_since(UNIX_EPOCH)
-		.unwrap()
-		.as_secs()
+		.unwrap_or_default()
+		.as_secs())
+}
+
+fn digest_revision(digest: &[u8]) -> u64 {
+	u64::from_le_bytes(digest[..8].try_into().unwrap())
+}
+
+/// Revision for content that was produced in memory, compatible with [file_content_hash]
+pub fn bytes_hash(bytes: &[u8]) -> u64 {
+	digest_revision(&Sha256::digest(bytes))
+}
+
+/// Streams the file through SHA-256 and folds the digest into a revision number.
+/// Digests are cached in SQLite by modification time and size.
+pub fn file_content_hash(db: &dyn Db, schema: Schema, path: &Path) -> std::io::Result<u64> {
+	path_content_hash(&db.sqlite_project(schema), path)
 }
 
+pub(crate) fn path_content_hash(sqlite: &Mutex<Connection>, path: &Path) -> std::io::Result<u64> {
+	let metadata = std::fs::metadata(path)?;
+	let mtime = metadata
+		.modified()?
+		.duration_since(UNIX_EPOCH)
+		.unwrap_or_default()
+		.as_nanos() as i64;
+	let size = metadata.len() as i64;
+
+	match sqlite_file_hash_get(&sqlite.lock(), path, mtime, size) {
+		Ok(Some(hash)) => return Ok(hash),
+		Ok(None) => {}
+		Err(e) => tracing::warn!("Failed to read cached hash for {:?}: {}", path, e),
+	}
+
+	let mut hasher = Sha256::new();
+	std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+	let hash = digest_revision(&hasher.finalize());
+
+	if let Err(e) = sqlite_file_hash_put(&sqlite.lock(), path, mtime, size, hash) {
+		tracing::warn!("Failed to cache hash for {:?}: {}", path, e);
+	}
+
+	Ok(hash)
+}
+
+/// Takes the SQLite connection instead of the database, so walks can read revisions
+/// without holding the database
+pub fn file_revision(
+	sqlite: &Mutex<Connection>,
+	path: &Path,
+	strategy: FileGroupStategy,
+) -> std::io::Result<u64> {
+	match strategy {
+		FileGroupStategy::Hash => path_content_hash(sqlite, path),
+		FileGroupStategy::Time => file_modified_time_in_seconds(path),
+	}
+}
+
+/// Files are registered by the walk and the watcher, revisions are kept up to date by them
 #[salsa::tracked]
 pub fn file_group_files(db: &dyn Db, schema: Schema, group: FileGroup) -> Outcome<Vec<File>> {
-	let files = file_group_resolved_paths(db, schema, group)?
-		.into_iter()
-		.map(|path| {
-			let revision = file_modified_time_in_seconds(&path);
-			File::new(db, path, revision)
-		})
-		.collect();
+	let files = file_group_resolved_files(db, schema, group)
+		.map_err(|e| e.context(format!("File group {:?}", group.name(db))))?;
+
+	if files.is_empty() {
+		let source = DiagnosticSource::FileGroup(group);
+		Diagnostics::report(db, DiagnosticSeverity::Warning, source, "Matches no files");
+	}
 
 	Outcome::Ok(files)
 }
+
+#[derive(Debug, Clone)]
+pub enum FileChange {
+	Created(PathBuf),
+	Modified(PathBuf),
+	Removed(PathBuf),
+}
+
+impl FileChange {
+	pub fn path(&self) -> &Path {
+		match self {
+			FileChange::Created(p) | FileChange::Modified(p) | FileChange::Removed(p) => p,
+		}
+	}
+}
+
+/// Applies filesystem changes to an already resolved root, so only the queries
+/// that depend on the affected files are recomputed.
+pub fn root_files_apply(db: &mut Database, schema: Schema, root: &Path, changes: &[FileChange]) {
+	// Roots that were not walked yet will see the changes during the walk
+	let Ok(mut files) = root_files(db, schema, root.to_owned()) else {
+		return;
+	};
+
+	let Ok(groups) = root_file_groups(db, schema, root.to_owned()) else {
+		return;
+	};
+
+	let matchers = groups
+		.into_iter()
+		.map(|(g, prefix)| Ok((g, file_group_matcher(db, g, prefix)?)))
+		.collect::<Outcome<BTreeMap<_, _>>>();
+
+	// Broken patterns are reported by the queries that use them
+	let Ok(matchers) = matchers else {
+		return;
+	};
+
+	let sqlite = db.sqlite_project(schema);
+	let mut changed = false;
+	let mut refresh = Vec::new();
+
+	for change in changes {
+		let path = change.path();
+		let Ok(relative) = path.strip_prefix(root) else {
+			continue;
+		};
+
+		if relative.components().any(|c| c.as_os_str() == KABINA_DIR) {
+			continue;
+		}
+
+		match change {
+			FileChange::Removed(path) => {
+				for group_files in files.values_mut() {
+					let len = group_files.len();
+					group_files.retain(|f| !f.path(db).starts_with(path));
+					changed |= group_files.len() != len;
+				}
+				db.files().retain(|p, _| !p.starts_with(path));
+			}
+			FileChange::Created(path) => {
+				// A directory may have been moved in together with its content
+				for (ignore_files, matchers) in partition_matchers(&matchers) {
+					if ignore_files && is_ignored_by_files(root, path) {
+						continue;
+					}
+
+					for entry in walk_root(root, path, ignore_files, &matchers) {
+						let Ok(relative) = entry.strip_prefix(root) else {
+							continue;
+						};
+
+						for (group, matcher) in &matchers {
+							if !matcher.is_match(relative) {
+								continue;
+							}
+
+							let strategy = matcher.strategy(relative);
+							let revision = match file_revision(&sqlite, &entry, strategy) {
+								Ok(revision) => revision,
+								Err(e) => {
+									tracing::warn!("Failed to read {:?}: {}", entry, e);
+									continue;
+								}
+							};
+
+							let file = db.file_update(entry.clone(), revision);
+							let group_files = files.entry(*group).or_default();
+							if !group_files.contains(&file) {
+								group_files.push(file);
+								changed = true;
+							}
+						}
+					}
+				}
+			}
+			FileChange::Modified(path) => {
+				for (group, group_files) in &files {
+					match matchers.get(group) {
+						Some(matcher) if group_files.iter().any(|f| f.path(db) == *path) => {
+							refresh.push((path.clone(), matcher.strategy(relative)))
+						}
+						_ => {}
+					}
+				}
+			}
+		}
+	}
+
+	for (path, strategy) in refresh {
+		if !db.files().contains_key(&path) {
+			continue;
+		}
+
+		match file_revision(&sqlite, &path, strategy) {
+			Ok(revision) => {
+				db.file_update(path, revision);
+			}
+			Err(e) => tracing::warn!("Failed to refresh {:?}: {}", path, e),
+		}
+	}
+
+	if changed {
+		root_files::set(db, schema, root.to_owned(), Ok(files))
+	}
+}
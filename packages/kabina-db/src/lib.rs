@@ -5,6 +5,7 @@ mod binary;
 mod collection;
 pub mod db;
 pub mod deps;
+mod diagnostic;
 mod error;
 mod fileset;
 pub mod runtime;
@@ -12,14 +13,17 @@ mod schema;
 mod server;
 mod service;
 mod sqlite;
+mod store;
 mod transform;
 
 pub use binary::*;
 pub use collection::*;
 pub use db::*;
+pub use diagnostic::*;
 pub use error::*;
 pub use fileset::*;
 pub use schema::*;
 pub use server::*;
 pub use service::*;
+pub use store::*;
 pub use transform::*;
@@ -1,8 +1,9 @@
 use url::Url;
 
-use crate::{File, Schema, TransformApply};
+use crate::{File, Outcome, Schema, TransformApply};
 
 pub trait Runtime {
-	async fn load_schema(&mut self, schema: Url) -> Schema;
-	async fn transform(&mut self, task: &TransformApply) -> File;
+	/// Evaluates the schema module, errors of the module and of its declarations are returned
+	async fn load_schema(&mut self, schema: Url) -> Result<Schema, anyhow::Error>;
+	async fn transform(&mut self, task: &TransformApply) -> Outcome<File>;
 }
@@ -34,9 +34,16 @@ pub struct SchemaBuilder {
 	pub servers: DashSet<Server>,
 	pub services: DashSet<Service>,
 	pub binaries: DashSet<Binary>,
+	/// Names taken by the objects of each kind
+	pub names: DashSet<(&'static str, String)>,
 }
 
 impl SchemaBuilder {
+	/// Takes the name for an object of the kind, false when another object has it already
+	pub fn name_claim(&self, kind: &'static str, name: &str) -> bool {
+		self.names.insert((kind, name.to_owned()))
+	}
+
 	pub fn register_file_group(&self, file_group: FileGroup) {
 		self.file_groups.insert(file_group);
 	}
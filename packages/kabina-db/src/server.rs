@@ -1,5 +1,131 @@
+use std::path::{Path, PathBuf};
+
+use crate::{Collection, Port};
+
+/// Port used by servers that do not configure one
+pub const SERVER_DEFAULT_PORT: u16 = 8080;
+
+/// File served for requests that point to a directory
+pub const SERVER_INDEX: &str = "index.html";
+
+#[derive(Debug, Clone)]
+pub struct ServerRoute {
+	/// Either an exact mount point like `/docs` or a prefix ending with `*`
+	pub pattern: String,
+	pub collection: Collection,
+}
+
 #[salsa::input]
 #[derive(Debug, Clone)]
 pub struct Server {
-	name: String,
+	pub name: String,
+	/// Port to listen on, `SERVER_DEFAULT_PORT` when not set
+	pub port: Option<Port>,
+	/// Routes ordered from the most specific one
+	pub routes: Vec<ServerRoute>,
+	/// Whether HTML responses get the live-reload client injected
+	pub live_reload: bool,
+}
+
+impl ServerRoute {
+	pub fn new(pattern: String, collection: Collection) -> Self {
+		ServerRoute {
+			pattern,
+			collection,
+		}
+	}
+
+	/// Maps the request path to a path inside of the route collection
+	pub fn resolve(&self, path: &str) -> Option<PathBuf> {
+		let path = path.trim_start_matches('/');
+		let pattern = self.pattern.trim_start_matches('/');
+
+		let relative = match pattern.strip_suffix('*') {
+			Some(prefix) => path.strip_prefix(prefix)?,
+			None if path == pattern => "",
+			None => path
+				.strip_prefix(pattern.trim_end_matches('/'))?
+				.strip_prefix('/')?,
+		};
+
+		if relative.is_empty() || relative.ends_with('/') {
+			return Some(PathBuf::from(relative).join(SERVER_INDEX));
+		}
+
+		Some(PathBuf::from(relative))
+	}
+
+	/// Maps a path inside of the route collection back to the request path
+	pub fn url(&self, path: &Path) -> String {
+		let pattern = self.pattern.trim_start_matches('/');
+		let prefix = match pattern.strip_suffix('*') {
+			Some(prefix) => prefix.to_owned(),
+			None if pattern.is_empty() => String::new(),
+			None => format!("{}/", pattern.trim_end_matches('/')),
+		};
+
+		let path = path.to_string_lossy().replace('\\', "/");
+		match path.strip_suffix(SERVER_INDEX) {
+			Some(dir) if dir.is_empty() || dir.ends_with('/') => format!("/{}{}", prefix, dir),
+			_ => format!("/{}{}", prefix, path),
+		}
+	}
+
+	fn specificity(&self) -> (usize, bool) {
+		let pattern = self.pattern.trim_start_matches('/');
+		match pattern.strip_suffix('*') {
+			Some(prefix) => (prefix.len(), false),
+			None => (pattern.len(), true),
+		}
+	}
+}
+
+/// Sorts routes so that the most specific ones are tried first
+pub fn server_routes_sort(routes: &mut [ServerRoute]) {
+	routes.sort_by(|a, b| b.specificity().cmp(&a.specificity()));
+}
+
+#[cfg(test)]
+mod tests {
+	use salsa::AsId;
+
+	use super::*;
+
+	fn route(pattern: &str) -> ServerRoute {
+		ServerRoute::new(pattern.into(), Collection::from_id(0usize.into()))
+	}
+
+	#[test]
+	fn test_route_resolve() {
+		let all = route("*");
+		assert_eq!(all.resolve("/"), Some(PathBuf::from("index.html")));
+		assert_eq!(all.resolve("/a/b.css"), Some(PathBuf::from("a/b.css")));
+		assert_eq!(all.resolve("/a/"), Some(PathBuf::from("a/index.html")));
+
+		let assets = route("/assets/*");
+		assert_eq!(assets.resolve("/assets/app.js"), Some(PathBuf::from("app.js")));
+		assert_eq!(assets.resolve("/app.js"), None);
+
+		let docs = route("/docs");
+		assert_eq!(docs.resolve("/docs"), Some(PathBuf::from("index.html")));
+		assert_eq!(docs.resolve("/docs/intro.html"), Some(PathBuf::from("intro.html")));
+		assert_eq!(docs.resolve("/docsify"), None);
+	}
+
+	#[test]
+	fn test_route_url() {
+		assert_eq!(route("*").url(Path::new("a/b.css")), "/a/b.css");
+		assert_eq!(route("*").url(Path::new("index.html")), "/");
+		assert_eq!(route("/assets/*").url(Path::new("app.js")), "/assets/app.js");
+		assert_eq!(route("/docs").url(Path::new("intro/index.html")), "/docs/intro/");
+	}
+
+	#[test]
+	fn test_routes_sort() {
+		let mut routes = vec![route("*"), route("/docs"), route("/assets/*")];
+		server_routes_sort(&mut routes);
+
+		let patterns = routes.iter().map(|r| r.pattern.as_str()).collect::<Vec<_>>();
+		assert_eq!(patterns, vec!["/assets/*", "/docs", "*"]);
+	}
 }
@@ -1,8 +1,221 @@
-use crate::Binary;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet};
+use std::hash::{Hash, Hasher};
+
+use thiserror::Error;
+
+use crate::{
+	collection_files, file_group_files, transform_files, Binary, Cause, Collection, Db, File,
+	FileGroup, Outcome, Schema, Transform,
+};
+
+/// What to do when the service process exits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RestartPolicy {
+	#[default]
+	Never,
+	/// Restart after a non-zero exit, waiting longer after every failure
+	OnFailure,
+	/// Restart after any exit, waiting longer if the service keeps exiting
+	Always,
+}
+
+impl RestartPolicy {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			RestartPolicy::Never => "never",
+			RestartPolicy::OnFailure => "on-failure",
+			RestartPolicy::Always => "always",
+		}
+	}
+}
+
+/// Where the service output goes besides the in-memory buffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ServiceLogConfig {
+	/// Write the output to a rotating log file
+	pub file: bool,
+	/// Pass stdout lines to the `processLogs.stdout` callback of the schema
+	pub stdout_callback: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReadinessProbe {
+	/// The port accepts connections
+	Tcp { host: String, port: u16 },
+	/// GET request to the URL returns a 2xx status
+	Http { url: String },
+	/// The service writes a line matching the regular expression
+	Log { pattern: String },
+	/// The command exits successfully
+	Exec { command: String, args: Vec<String> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Readiness {
+	pub probe: ReadinessProbe,
+	/// Delay between probes in milliseconds
+	pub interval: u64,
+	/// Consecutive failed probes after which the service becomes unhealthy, a service which
+	/// never passes its probe is unhealthy too
+	pub failure_threshold: u32,
+}
+
+/// Files the service is restarted after
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServiceInput {
+	FileGroup(FileGroup),
+	Transform(Transform),
+	Collection(Collection),
+}
 
 #[salsa::input]
 #[derive(Debug, Clone)]
 pub struct Service {
 	pub name: String,
 	pub binary: Binary,
+	pub restart: RestartPolicy,
+	pub logs: ServiceLogConfig,
+	pub readiness: Option<Readiness>,
+	/// Services that have to be ready before this one starts
+	pub depends_on: Vec<Service>,
+	pub inputs: Vec<ServiceInput>,
+	/// Binary that is run before the service is started and after its inputs change
+	pub build: Option<Binary>,
+	/// Milliseconds to wait after SIGTERM before the process is killed
+	pub stop_timeout: u64,
+	/// Named ports other services can reference as `${name.port}` or `${name.ports.port}`
+	pub ports: BTreeMap<String, Port>,
+}
+
+/// Port of a service or a server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Port {
+	Fixed(u16),
+	/// Allocated by the daemon when the schema is loaded
+	Auto,
+}
+
+impl Port {
+	pub fn fixed(self) -> Option<u16> {
+		match self {
+			Port::Fixed(port) => Some(port),
+			Port::Auto => None,
+		}
+	}
+}
+
+impl Service {
+	/// Ports that are already known, automatic ones are missing until they are allocated
+	pub fn fixed_ports(self, db: &dyn Db) -> BTreeMap<String, u16> {
+		self.ports(db)
+			.into_iter()
+			.filter_map(|(name, port)| port.fixed().map(|p| (name, p)))
+			.collect()
+	}
+
+	/// Port referenced as `${name.port}`, the `http` port or the only port of the service
+	pub fn default_port(self, db: &dyn Db) -> Option<u16> {
+		let ports = self.ports(db);
+		match ports.get("http") {
+			Some(port) => port.fixed(),
+			None if ports.len() == 1 => ports.values().next().and_then(|p| p.fixed()),
+			None => None,
+		}
+	}
+}
+
+/// Combined revision of every input file, changes whenever any of them does
+#[salsa::tracked]
+pub fn service_inputs_revision(db: &dyn Db, schema: Schema, service: Service) -> Outcome<u64> {
+	service_inputs_hash(db, schema, service)
+		.map_err(|e| e.context(format!("Inputs of service {:?}", service.name(db))))
+}
+
+fn service_inputs_hash(db: &dyn Db, schema: Schema, service: Service) -> Outcome<u64> {
+	let mut pending = false;
+	let mut hasher = DefaultHasher::new();
+
+	let mut hash_files = |files: Outcome<Vec<File>>| -> Outcome<()> {
+		match files {
+			Ok(files) => {
+				for file in files {
+					file.path(db).hash(&mut hasher);
+					file.revision(db).hash(&mut hasher);
+				}
+			}
+			Err(Cause::Pending) => pending = true,
+			Err(e) => return Err(e),
+		}
+
+		Ok(())
+	};
+
+	for input in service.inputs(db) {
+		match input {
+			ServiceInput::FileGroup(g) => hash_files(file_group_files(db, schema, g))?,
+			ServiceInput::Transform(t) => hash_files(transform_files(db, schema, t))?,
+			ServiceInput::Collection(c) => {
+				hash_files(collection_files(db, schema, c).map(|f| f.into_values().collect()))?
+			}
+		}
+	}
+
+	if pending {
+		return Outcome::Err(Cause::Pending);
+	}
+
+	Ok(hasher.finish())
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("Services depend on each other: {}", .0.join(" -> "))]
+pub struct ServiceCycle(pub Vec<String>);
+
+/// Orders the schema services so that every service comes after its dependencies
+pub fn services_start_order(db: &dyn Db, schema: Schema) -> Result<Vec<Service>, ServiceCycle> {
+	let mut services = schema.services(db).iter().map(|s| *s).collect::<Vec<_>>();
+	services.sort_by_key(|s| (s.name(db), *s));
+
+	let mut order = Vec::new();
+	let mut visited = BTreeSet::new();
+	let mut path = Vec::new();
+
+	for service in services.iter() {
+		services_visit(db, *service, &services, &mut visited, &mut path, &mut order)?;
+	}
+
+	Ok(order)
+}
+
+fn services_visit(
+	db: &dyn Db,
+	service: Service,
+	known: &[Service],
+	visited: &mut BTreeSet<Service>,
+	path: &mut Vec<Service>,
+	order: &mut Vec<Service>,
+) -> Result<(), ServiceCycle> {
+	if visited.contains(&service) {
+		return Ok(());
+	}
+
+	if let Some(start) = path.iter().position(|s| *s == service) {
+		let mut names = path[start..].iter().map(|s| s.name(db)).collect::<Vec<_>>();
+		names.push(service.name(db));
+		return Err(ServiceCycle(names));
+	}
+
+	path.push(service);
+	for dependency in service.depends_on(db) {
+		// Services of other schemas are started by them
+		if known.contains(&dependency) {
+			services_visit(db, dependency, known, visited, path, order)?;
+		}
+	}
+	path.pop();
+
+	visited.insert(service);
+	order.push(service);
+	Ok(())
 }
@@ -1,30 +1,89 @@
-use rusqlite::{params, Connection};
+use std::path::{Path, PathBuf};
+
+use rusqlite::{params, Connection, OptionalExtension};
 use rusqlite_migration::{Migrations, M};
 use url::Url;
 
 fn migrations() -> Migrations<'static> {
-	Migrations::new(vec![M::up(
-		r#"
+	Migrations::new(vec![
+		M::up(
+			r#"
         CREATE TABLE schema_files (url TEXT NOT NULL);
         "#,
-	)])
+		),
+		M::up(
+			r#"
+        CREATE TABLE file_hashes (
+            path TEXT PRIMARY KEY NOT NULL,
+            mtime INTEGER NOT NULL,
+            size INTEGER NOT NULL,
+            hash INTEGER NOT NULL
+        );
+        "#,
+		),
+		M::up(
+			r#"
+        CREATE UNIQUE INDEX schema_files_url ON schema_files (url);
+        CREATE TABLE transform_outputs (
+            schema TEXT NOT NULL,
+            transform TEXT NOT NULL,
+            input TEXT NOT NULL,
+            input_revision INTEGER NOT NULL,
+            dependencies INTEGER NOT NULL,
+            output TEXT NOT NULL,
+            output_revision INTEGER NOT NULL,
+            PRIMARY KEY (schema, transform, input, input_revision, dependencies)
+        );
+        "#,
+		),
+		M::up(
+			r#"
+        CREATE TABLE transform_cache (
+            schema TEXT NOT NULL,
+            transform TEXT NOT NULL,
+            input_name TEXT NOT NULL,
+            input_digest INTEGER NOT NULL,
+            dependencies INTEGER NOT NULL,
+            file_name TEXT NOT NULL,
+            blob TEXT NOT NULL,
+            output_revision INTEGER NOT NULL,
+            PRIMARY KEY (schema, transform, input_name, input_digest, dependencies)
+        );
+        "#,
+		),
+	])
 }
 
-pub fn sqlite_setup() -> Result<Connection, anyhow::Error> {
+fn sqlite_init(mut conn: Connection) -> Result<Connection, anyhow::Error> {
 	let migrations = migrations();
-	let mut conn = Connection::open_in_memory()?;
 	conn.pragma_update(None, "journal_mode", &"WAL")?;
 	migrations.to_latest(&mut conn)?;
 
 	Ok(conn)
 }
 
+pub fn sqlite_setup() -> Result<Connection, anyhow::Error> {
+	sqlite_init(Connection::open_in_memory()?)
+}
+
+/// Opens (or creates) an on-disk database and migrates it to the latest version
+pub fn sqlite_open(path: &Path) -> Result<Connection, anyhow::Error> {
+	if let Some(parent) = path.parent() {
+		std::fs::create_dir_all(parent)?;
+	}
+
+	sqlite_init(Connection::open(path)?)
+}
+
 pub struct SqliteSchema {
 	pub url: Url,
 }
 
 pub fn sqlite_schema_add(c: &Connection, url: &Url) -> anyhow::Result<()> {
-	c.execute("INSERT INTO schema_files (url) VALUES (?1);", params![url])?;
+	c.execute(
+		"INSERT OR IGNORE INTO schema_files (url) VALUES (?1);",
+		params![url],
+	)?;
 	Ok(())
 }
 
@@ -43,6 +102,169 @@ pub fn sqlite_schema_remove(c: &Connection, url: &Url) -> anyhow::Result<()> {
 	Ok(())
 }
 
+/// Returns a cached content hash if the file was not modified since it was computed
+pub fn sqlite_file_hash_get(
+	c: &Connection,
+	path: &Path,
+	mtime: i64,
+	size: i64,
+) -> anyhow::Result<Option<u64>> {
+	let hash = c
+		.query_row(
+			"SELECT hash FROM file_hashes WHERE path = ?1 AND mtime = ?2 AND size = ?3;",
+			params![path.to_string_lossy(), mtime, size],
+			|r| r.get::<_, i64>("hash"),
+		)
+		.optional()?;
+
+	Ok(hash.map(|h| h as u64))
+}
+
+pub fn sqlite_file_hash_put(
+	c: &Connection,
+	path: &Path,
+	mtime: i64,
+	size: i64,
+	hash: u64,
+) -> anyhow::Result<()> {
+	c.execute(
+		"INSERT OR REPLACE INTO file_hashes (path, mtime, size, hash) VALUES (?1, ?2, ?3, ?4);",
+		params![path.to_string_lossy(), mtime, size, hash as i64],
+	)?;
+	Ok(())
+}
+
+/// Identity of a transform run: the transform, its input file and its dependencies
+pub struct SqliteTransformOutputKey<'a> {
+	pub schema: &'a Url,
+	pub transform: &'a str,
+	pub input: &'a Path,
+	pub input_revision: u64,
+	pub dependencies: u64,
+}
+
+pub fn sqlite_transform_output_get(
+	c: &Connection,
+	key: &SqliteTransformOutputKey,
+) -> anyhow::Result<Option<(PathBuf, u64)>> {
+	let output = c
+		.query_row(
+			"SELECT output, output_revision FROM transform_outputs
+			WHERE schema = ?1 AND transform = ?2 AND input = ?3
+			AND input_revision = ?4 AND dependencies = ?5;",
+			params![
+				key.schema,
+				key.transform,
+				key.input.to_string_lossy(),
+				key.input_revision as i64,
+				key.dependencies as i64
+			],
+			|r| {
+				Ok((
+					PathBuf::from(r.get::<_, String>("output")?),
+					r.get::<_, i64>("output_revision")? as u64,
+				))
+			},
+		)
+		.optional()?;
+
+	Ok(output)
+}
+
+pub fn sqlite_transform_output_put(
+	c: &Connection,
+	key: &SqliteTransformOutputKey,
+	output: &Path,
+	output_revision: u64,
+) -> anyhow::Result<()> {
+	c.execute(
+		"INSERT OR REPLACE INTO transform_outputs
+		(schema, transform, input, input_revision, dependencies, output, output_revision)
+		VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7);",
+		params![
+			key.schema,
+			key.transform,
+			key.input.to_string_lossy(),
+			key.input_revision as i64,
+			key.dependencies as i64,
+			output.to_string_lossy(),
+			output_revision as i64
+		],
+	)?;
+	Ok(())
+}
+
+/// Identity of a transform run by content: the transform, the path and the digest of its
+/// input file and its dependencies
+pub struct SqliteTransformCacheKey<'a> {
+	pub schema: &'a Url,
+	pub transform: &'a str,
+	/// Path of the input relative to its input root
+	pub input_name: &'a Path,
+	pub input_digest: u64,
+	pub dependencies: u64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct SqliteTransformCacheEntry {
+	pub file_name: PathBuf,
+	pub blob: String,
+	pub output_revision: u64,
+}
+
+pub fn sqlite_transform_cache_get(
+	c: &Connection,
+	key: &SqliteTransformCacheKey,
+) -> anyhow::Result<Option<SqliteTransformCacheEntry>> {
+	let entry = c
+		.query_row(
+			"SELECT file_name, blob, output_revision FROM transform_cache
+			WHERE schema = ?1 AND transform = ?2 AND input_name = ?3 AND input_digest = ?4
+			AND dependencies = ?5;",
+			params![
+				key.schema,
+				key.transform,
+				key.input_name.to_string_lossy(),
+				key.input_digest as i64,
+				key.dependencies as i64
+			],
+			|r| {
+				Ok(SqliteTransformCacheEntry {
+					file_name: PathBuf::from(r.get::<_, String>("file_name")?),
+					blob: r.get("blob")?,
+					output_revision: r.get::<_, i64>("output_revision")? as u64,
+				})
+			},
+		)
+		.optional()?;
+
+	Ok(entry)
+}
+
+pub fn sqlite_transform_cache_put(
+	c: &Connection,
+	key: &SqliteTransformCacheKey,
+	entry: &SqliteTransformCacheEntry,
+) -> anyhow::Result<()> {
+	c.execute(
+		"INSERT OR REPLACE INTO transform_cache
+		(schema, transform, input_name, input_digest, dependencies, file_name, blob,
+		output_revision)
+		VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8);",
+		params![
+			key.schema,
+			key.transform,
+			key.input_name.to_string_lossy(),
+			key.input_digest as i64,
+			key.dependencies as i64,
+			entry.file_name.to_string_lossy(),
+			entry.blob,
+			entry.output_revision as i64
+		],
+	)?;
+	Ok(())
+}
+
 #[cfg(test)]
 mod tests {
 	use std::path::PathBuf;
@@ -62,11 +284,95 @@ mod tests {
 		let url = Url::from_file_path(PathBuf::from("/test/a.ts")).unwrap();
 		sqlite_schema_add(&c, &url)?;
 
+		// Registering the same schema twice is a no-op
+		sqlite_schema_add(&c, &url)?;
+
 		let schemas = sqlite_schema_all(&c)?;
+		assert_eq!(schemas.len(), 1);
 		assert_eq!(schemas[0].url, url);
 
 		sqlite_schema_remove(&c, &url)?;
 
 		Ok(())
 	}
+
+	#[test]
+	fn test_sqlite_file_hash() -> Result<(), anyhow::Error> {
+		let c = sqlite_setup().unwrap();
+		let path = PathBuf::from("/test/a.ts");
+		sqlite_file_hash_put(&c, &path, 10, 20, u64::MAX)?;
+
+		assert_eq!(sqlite_file_hash_get(&c, &path, 10, 20)?, Some(u64::MAX));
+		assert_eq!(sqlite_file_hash_get(&c, &path, 11, 20)?, None);
+
+		sqlite_file_hash_put(&c, &path, 11, 20, 42)?;
+		assert_eq!(sqlite_file_hash_get(&c, &path, 11, 20)?, Some(42));
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_sqlite_transform_output() -> Result<(), anyhow::Error> {
+		let c = sqlite_setup().unwrap();
+		let url = Url::from_file_path(PathBuf::from("/test/a.ts")).unwrap();
+		let input = PathBuf::from("/test/a.css");
+		let output = PathBuf::from("/test/.kabina/transforms/css/a.css");
+
+		let key = SqliteTransformOutputKey {
+			schema: &url,
+			transform: "css",
+			input: &input,
+			input_revision: 1,
+			dependencies: 2,
+		};
+
+		assert_eq!(sqlite_transform_output_get(&c, &key)?, None);
+
+		sqlite_transform_output_put(&c, &key, &output, 3)?;
+		assert_eq!(sqlite_transform_output_get(&c, &key)?, Some((output, 3)));
+
+		let key = SqliteTransformOutputKey {
+			input_revision: 2,
+			..key
+		};
+		assert_eq!(sqlite_transform_output_get(&c, &key)?, None);
+
+		Ok(())
+	}
+
+	#[test]
+	fn test_sqlite_transform_cache() -> Result<(), anyhow::Error> {
+		let c = sqlite_setup().unwrap();
+		let url = Url::from_file_path(PathBuf::from("/test/a.ts")).unwrap();
+
+		let input_name = PathBuf::from("a.css");
+		let key = SqliteTransformCacheKey {
+			schema: &url,
+			transform: "css@0000000000000001",
+			input_name: &input_name,
+			input_digest: u64::MAX,
+			dependencies: 2,
+		};
+
+		assert_eq!(sqlite_transform_cache_get(&c, &key)?, None);
+
+		let entry = SqliteTransformCacheEntry {
+			file_name: PathBuf::from("css/a.css"),
+			blob: String::from("abcdef"),
+			output_revision: 3,
+		};
+
+		sqlite_transform_cache_put(&c, &key, &entry)?;
+		assert_eq!(sqlite_transform_cache_get(&c, &key)?, Some(entry));
+
+		// The same content at another path is a different input
+		let other = PathBuf::from("b.css");
+		let key = SqliteTransformCacheKey {
+			input_name: &other,
+			..key
+		};
+		assert_eq!(sqlite_transform_cache_get(&c, &key)?, None);
+
+		Ok(())
+	}
 }
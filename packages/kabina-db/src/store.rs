@@ -0,0 +1,51 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+/// Local store of blobs addressed by the SHA-256 of their content
+#[derive(Debug, Clone)]
+pub struct ContentStore {
+	root: PathBuf,
+}
+
+impl ContentStore {
+	pub fn new(root: PathBuf) -> Self {
+		ContentStore { root }
+	}
+
+	pub fn blob_path(&self, digest: &str) -> PathBuf {
+		self.root.join("blobs").join(&digest[..2]).join(&digest[2..])
+	}
+
+	/// Returns the blob path if the store has it
+	pub fn get(&self, digest: &str) -> Option<PathBuf> {
+		let path = self.blob_path(digest);
+		path.is_file().then_some(path)
+	}
+
+	/// Copies the file into the store and returns its digest
+	pub fn put_file(&self, path: &Path) -> io::Result<String> {
+		let mut hasher = Sha256::new();
+		io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+		let digest = hasher
+			.finalize()
+			.iter()
+			.map(|b| format!("{:02x}", b))
+			.collect::<String>();
+
+		let blob = self.blob_path(&digest);
+		if !blob.is_file() {
+			if let Some(parent) = blob.parent() {
+				std::fs::create_dir_all(parent)?;
+			}
+
+			// Write under a temporary name, so readers never see partial blobs
+			let tmp = blob.with_extension("tmp");
+			std::fs::copy(path, &tmp)?;
+			std::fs::rename(&tmp, &blob)?;
+		}
+
+		Ok(digest)
+	}
+}
@@ -1,15 +1,34 @@
 use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
 
+use anyhow::{anyhow, Context};
+use parking_lot::Mutex;
+use rusqlite::Connection;
+use salsa::AsId;
 use serde_json::Value;
+use url::Url;
 
 use crate::deps::{
 	extract_dependencies, replace_dependencies, Dependency, Input, ResolvedDependency,
 };
+use crate::fileset::path_content_hash;
+use crate::sqlite::{
+	sqlite_transform_cache_get, sqlite_transform_cache_put, sqlite_transform_output_get,
+	sqlite_transform_output_put, SqliteTransformCacheEntry, SqliteTransformCacheKey,
+	SqliteTransformOutputKey,
+};
+use crate::store::ContentStore;
 use crate::{
-	binary_resolve, file_group_files, Cause, Db, Executable, File, Outcome, RuntimeTask, Schema,
+	binary_resolve, bytes_hash, file_content_hash, file_group_files, BinaryRuntimeResolved, Cause,
+	Database, Db, DiagnosticSeverity, DiagnosticSource, Diagnostics, Executable, ExecutableKind,
+	File, Outcome, RuntimeTask, Schema, Span,
 };
 
+/// Name of the per-schema directory where kabina keeps generated files
+pub const KABINA_DIR: &str = ".kabina";
+
 #[derive(Debug, Clone)]
 pub enum RunnerKind {
 	JsFunction(u64),
@@ -18,10 +37,19 @@ pub enum RunnerKind {
 #[salsa::input]
 #[derive(Debug, Clone)]
 pub struct Transform {
-	name: String,
+	pub name: String,
 	runner: RunnerKind,
 	input: Value,
 	dependencies: Value,
+	/// Hash of the transform source, changes whenever the transform code does
+	fingerprint: u64,
+	/// Declaration in the schema module, diagnostics point at it
+	pub span: Option<Span>,
+}
+
+/// Identifies a transform across daemon restarts and checkouts
+pub fn transform_identity(db: &dyn Db, transform: Transform) -> String {
+	format!("{}@{:016x}", transform.name(db), transform.fingerprint(db))
 }
 
 #[salsa::tracked]
@@ -56,8 +84,12 @@ pub fn transform_dependencies(
 				}
 				Err(c) => error = Err(c),
 			},
-			Dependency::FileGroup(_) => todo!(),
-			Dependency::Transform(_) => todo!(),
+			Dependency::FileGroup(_) | Dependency::Transform(_) => {
+				let message = "Only binaries can be transform dependencies, files are inputs";
+				let source = DiagnosticSource::Transform(transform);
+				Diagnostics::report(db, DiagnosticSeverity::Error, source, message);
+				error = Err(Cause::from_anyhow(anyhow!(message)));
+			}
 		}
 	}
 
@@ -67,8 +99,34 @@ pub fn transform_dependencies(
 	Ok(Arc::new(deps))
 }
 
+#[salsa::tracked]
+pub fn transform_output_dir(db: &dyn Db, schema: Schema, transform: Transform) -> PathBuf {
+	let module = PathBuf::from(schema.url(db).path());
+	let root = module.parent().map(Path::to_owned).unwrap_or_default();
+
+	let name = transform
+		.name(db)
+		.chars()
+		.map(|c| match c {
+			'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+			_ => '_',
+		})
+		.collect::<String>();
+
+	root.join(KABINA_DIR).join("transforms").join(name)
+}
+
 #[salsa::tracked]
 pub fn transform_files(db: &dyn Db, schema: Schema, transform: Transform) -> Outcome<Vec<File>> {
+	transform_files_results(db, schema, transform)
+		.map_err(|e| e.context(format!("Transform {:?}", transform.name(db))))
+}
+
+fn transform_files_results(
+	db: &dyn Db,
+	schema: Schema,
+	transform: Transform,
+) -> Outcome<Vec<File>> {
 	let inputs = transform_inputs(db, transform);
 
 	let mut pending = false;
@@ -118,26 +176,421 @@ pub fn transform_result_for_file(
 	schema: Schema,
 	transform: Transform,
 	file: File,
+) -> Outcome<File> {
+	// Results are stored per revision, so a changed file schedules a new run
+	let revision = file.revision(db);
+	transform_result_for_revision(db, schema, transform, file, revision)
+}
+
+#[salsa::tracked]
+pub fn transform_result_for_revision(
+	db: &dyn Db,
+	schema: Schema,
+	transform: Transform,
+	file: File,
+	revision: u64,
 ) -> Outcome<File> {
 	let dependencies = transform_dependencies(db, schema, transform)?;
 
+	// The most specific input root that contains the file
+	let path = file.path(db);
+	let file_name = transform_inputs(db, transform)
+		.into_iter()
+		.map(|input| input.root(db, schema))
+		.filter(|root| path.starts_with(root))
+		.max_by_key(|root| root.components().count())
+		.and_then(|root| path.strip_prefix(root).ok().map(Path::to_owned))
+		.unwrap_or_else(|| PathBuf::from(path.file_name().unwrap_or_default()));
+
+	let digest = dependencies_digest(db, schema, transform, &dependencies);
+
+	// Outputs of previous runs are looked up by the task, they are not tracked by the queries
 	RuntimeTask::push(
 		db,
 		Arc::new(TransformApply {
 			schema,
 			file,
 			transform,
+			revision,
 			dependencies,
+			digest,
+			file_name,
+			output: transform_output_dir(db, schema, transform),
 		}),
 	);
 	Outcome::Err(Cause::Pending)
 }
 
+/// Digest of the resolved dependencies and of the binaries they run, so a binary upgraded
+/// in place does not reuse the outputs of the previous version
+fn dependencies_digest(
+	db: &dyn Db,
+	schema: Schema,
+	transform: Transform,
+	dependencies: &Value,
+) -> u64 {
+	let mut digest = dependencies.to_string();
+
+	let mut buffer = Vec::new();
+	extract_dependencies(&transform.dependencies(db), &mut buffer);
+	for dep in buffer {
+		let Dependency::Toolchain(binary) = dep else {
+			continue;
+		};
+
+		// Dependencies are resolved already, reading the executable revision tracks it
+		let Ok(BinaryRuntimeResolved::Native {
+			executable_file, ..
+		}) = binary_resolve(db, schema, binary)
+		else {
+			continue;
+		};
+
+		digest.push_str(&format!(" {:016x}", executable_file.revision(db)));
+	}
+
+	bytes_hash(digest.as_bytes())
+}
+
+/// What the lookup of previous outputs needs from the database, taken while it is locked,
+/// so the lookup itself runs without it
+pub struct TransformLookup {
+	task: TransformApply,
+	sqlite: Arc<Mutex<Connection>>,
+	store: Option<ContentStore>,
+	url: Url,
+	identity: String,
+	input: PathBuf,
+}
+
+impl TransformLookup {
+	/// Output of a previous run for the same input and dependencies, either still in place
+	/// or in the content-addressed store
+	pub fn find(&self) -> Option<TransformRestore> {
+		let task = &self.task;
+		let restore = |target, blob, output_revision| TransformRestore {
+			schema: task.schema,
+			file: task.file,
+			transform: task.transform,
+			revision: task.revision,
+			file_name: task.file_name.clone(),
+			digest: task.digest,
+			blob,
+			target,
+			output_revision,
+		};
+
+		if let Some((target, output_revision)) = self.cached_output() {
+			tracing::info!("Reusing output of a previous run for {:?}", self.input);
+			return Some(restore(target, None, output_revision));
+		}
+
+		let entry = self.stored_output()?;
+		tracing::info!("Restoring output for {:?} from the store", self.input);
+		let target = task.output.join(entry.file_name);
+		Some(restore(target, Some(entry.blob), entry.output_revision))
+	}
+
+	/// Output stored by a previous run for the same input revision, with its revision
+	/// when it is still in place
+	fn cached_output(&self) -> Option<(PathBuf, u64)> {
+		let key = SqliteTransformOutputKey {
+			schema: &self.url,
+			transform: &self.identity,
+			input: &self.input,
+			input_revision: self.task.revision,
+			dependencies: self.task.digest,
+		};
+
+		let cached = sqlite_transform_output_get(&self.sqlite.lock(), &key);
+		let (output, output_revision) = match cached {
+			Ok(cached) => cached?,
+			Err(e) => {
+				tracing::warn!("Failed to read cached output for {:?}: {}", self.input, e);
+				return None;
+			}
+		};
+
+		// The output could have been changed or removed since
+		match path_content_hash(&self.sqlite, &output) {
+			Ok(r) if r == output_revision => Some((output, output_revision)),
+			_ => None,
+		}
+	}
+
+	/// Output in the content-addressed store by the digest of the input, so outputs
+	/// survive checkouts that only change modification times
+	fn stored_output(&self) -> Option<SqliteTransformCacheEntry> {
+		let store = self.store.as_ref()?;
+		let input_digest = path_content_hash(&self.sqlite, &self.input).ok()?;
+
+		let key = SqliteTransformCacheKey {
+			schema: &self.url,
+			transform: &self.identity,
+			input_name: &self.task.file_name,
+			input_digest,
+			dependencies: self.task.digest,
+		};
+
+		let entry = match sqlite_transform_cache_get(&self.sqlite.lock(), &key) {
+			Ok(entry) => entry?,
+			Err(e) => {
+				tracing::warn!("Failed to read stored output for {:?}: {}", self.input, e);
+				return None;
+			}
+		};
+
+		// Blobs could have been removed from the store
+		store.get(&entry.blob)?;
+		Some(entry)
+	}
+}
+
+/// Remembers the output of a run, both for the exact input revision and by content
+fn transform_output_put(
+	db: &dyn Db,
+	schema: Schema,
+	transform: Transform,
+	input: &Path,
+	input_name: &Path,
+	revision: u64,
+	dependencies: u64,
+	output: File,
+) -> Result<(), anyhow::Error> {
+	let url = schema.url(db);
+	let identity = transform_identity(db, transform);
+	let output_path = output.path(db);
+	let output_revision = output.revision(db);
+
+	let key = SqliteTransformOutputKey {
+		schema: &url,
+		transform: &identity,
+		input,
+		input_revision: revision,
+		dependencies,
+	};
+
+	let sqlite = db.sqlite_project(schema);
+	sqlite_transform_output_put(&sqlite.lock(), &key, &output_path, output_revision)?;
+
+	let Some(store) = db.store() else {
+		return Ok(());
+	};
+
+	let blob = store.put_file(&output_path)?;
+	let key = SqliteTransformCacheKey {
+		schema: &url,
+		transform: &identity,
+		input_name,
+		input_digest: file_content_hash(db, schema, input)?,
+		dependencies,
+	};
+
+	let file_name = output_path
+		.strip_prefix(transform_output_dir(db, schema, transform))
+		.map(Path::to_owned)
+		.unwrap_or_else(|_| PathBuf::from(output_path.file_name().unwrap_or_default()));
+
+	let entry = SqliteTransformCacheEntry {
+		file_name,
+		blob,
+		output_revision,
+	};
+
+	sqlite_transform_cache_put(&sqlite.lock(), &key, &entry)
+}
+
+#[derive(Clone)]
 pub struct TransformApply {
 	pub schema: Schema,
 	pub file: File,
 	pub transform: Transform,
+	pub revision: u64,
 	pub dependencies: Arc<Value>,
+	/// Digest of the dependencies the outputs are stored by
+	pub digest: u64,
+	/// Path of the input file relative to its input root
+	pub file_name: PathBuf,
+	/// Directory where the results of the transform are written
+	pub output: PathBuf,
+}
+
+impl Executable for TransformApply {
+	fn schema(&self) -> Schema {
+		self.schema
+	}
+
+	fn kind(&self) -> ExecutableKind {
+		ExecutableKind::Transform
+	}
+
+	fn name(&self, db: &dyn Db) -> String {
+		format!("{} {}", self.transform.name(db), self.file_name.display())
+	}
+}
+
+impl fmt::Debug for TransformApply {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("TransformApply")
+			.field("transform", &self.transform.as_id())
+			.field("file", &self.file.as_id())
+			.field("revision", &self.revision)
+			.field("file_name", &self.file_name)
+			.finish()
+	}
+}
+
+impl TransformApply {
+	/// Takes what the lookup of outputs of previous runs needs from the database
+	pub fn lookup(&self, db: &dyn Db) -> TransformLookup {
+		TransformLookup {
+			task: self.clone(),
+			sqlite: db.sqlite_project(self.schema),
+			store: db.store().cloned(),
+			url: self.schema.url(db),
+			identity: transform_identity(db, self.transform),
+			input: self.file.path(db),
+		}
+	}
+
+	/// Writes the transform result to the output directory and registers it as a file
+	pub fn write(
+		&self,
+		db: &mut Database,
+		file_name: &Path,
+		content: &[u8],
+	) -> std::io::Result<File> {
+		// Outputs stay inside the transform directory, whatever name the transform picks
+		let contained = file_name
+			.components()
+			.all(|c| matches!(c, Component::Normal(_)));
+		if !contained || file_name.as_os_str().is_empty() {
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::InvalidInput,
+				format!("Output file name {:?} is not a relative path", file_name),
+			));
+		}
+
+		let path = self.output.join(file_name);
+		if let Some(parent) = path.parent() {
+			std::fs::create_dir_all(parent)?;
+		}
+
+		std::fs::write(&path, content)?;
+		Ok(db.file_update(path, bytes_hash(content)))
+	}
+
+	pub fn resolve(&self, db: &mut dyn Db, object: Outcome<File>) {
+		if let Ok(output) = &object {
+			let input = self.file.path(db);
+			let stored = transform_output_put(
+				db,
+				self.schema,
+				self.transform,
+				&input,
+				&self.file_name,
+				self.revision,
+				self.digest,
+				*output,
+			);
+
+			if let Err(e) = stored {
+				tracing::warn!("Failed to store output for {:?}: {}", input, e);
+			}
+		}
+
+		transform_result_for_revision::set(
+			db,
+			self.schema,
+			self.transform,
+			self.file,
+			self.revision,
+			object,
+		)
+	}
 }
 
-impl Executable for TransformApply {}
+/// Output of a previous run used instead of running the transform, copied from the
+/// content-addressed store when it is not in place
+pub struct TransformRestore {
+	pub schema: Schema,
+	pub file: File,
+	pub transform: Transform,
+	pub revision: u64,
+	/// Path of the input file relative to its input root
+	pub file_name: PathBuf,
+	/// Digest of the dependencies the outputs are stored by
+	pub digest: u64,
+	/// Missing when the output of the previous run is still in place
+	pub blob: Option<String>,
+	pub target: PathBuf,
+	pub output_revision: u64,
+}
+
+impl TransformRestore {
+	/// Blob to copy into place, `None` when the output of the previous run is still there
+	pub fn source(&self, db: &dyn Db) -> std::io::Result<Option<PathBuf>> {
+		let Some(blob) = &self.blob else {
+			return Ok(None);
+		};
+
+		let store = db.store().ok_or_else(|| {
+			let message = "Content store is not configured";
+			std::io::Error::new(std::io::ErrorKind::NotFound, message)
+		})?;
+
+		Ok(Some(store.blob_path(blob)))
+	}
+
+	/// Copies the blob to the target, runs without the database
+	pub fn copy(&self, source: Option<&Path>) -> std::io::Result<()> {
+		if let Some(source) = source {
+			if let Some(parent) = self.target.parent() {
+				std::fs::create_dir_all(parent)?;
+			}
+
+			std::fs::copy(source, &self.target)?;
+		}
+
+		Ok(())
+	}
+
+	/// Records the output restored by `copy` as the result of the transform
+	pub fn restore(&self, db: &mut Database, copied: std::io::Result<()>) -> Outcome<()> {
+		let restored = copied
+			.map(|()| db.file_update(self.target.clone(), self.output_revision))
+			.with_context(|| format!("Failed to restore {:?} from the store", self.target))
+			.map_err(Cause::from_anyhow);
+		let status = restored.as_ref().map(|_| ()).map_err(Clone::clone);
+
+		// Outputs that are still in place are stored already
+		if let (Some(_), Ok(output)) = (&self.blob, &restored) {
+			let input = self.file.path(db);
+			let stored = transform_output_put(
+				&*db,
+				self.schema,
+				self.transform,
+				&input,
+				&self.file_name,
+				self.revision,
+				self.digest,
+				*output,
+			);
+
+			if let Err(e) = stored {
+				tracing::warn!("Failed to store output for {:?}: {}", input, e);
+			}
+		}
+
+		transform_result_for_revision::set(
+			db,
+			self.schema,
+			self.transform,
+			self.file,
+			self.revision,
+			restored,
+		);
+
+		status
+	}
+}
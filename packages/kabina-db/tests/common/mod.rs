@@ -0,0 +1,29 @@
+//! Fixtures shared by the integration tests
+#![allow(dead_code)]
+
+use std::path::PathBuf;
+
+use kabina_db::{Database, FileGroup, Schema, Service};
+use url::Url;
+
+/// Schema of a test project with only the given file groups and services
+pub fn schema_with(db: &Database, file_groups: &[FileGroup], services: &[Service]) -> Schema {
+	Schema::new(
+		db,
+		Url::from_file_path("/test").unwrap(),
+		file_groups.iter().copied().collect(),
+		Default::default(),
+		Default::default(),
+		Default::default(),
+		services.iter().copied().collect(),
+		Default::default(),
+	)
+}
+
+/// Empty directory for the test, left behind by a previous run is removed first
+pub fn temp_root(name: &str) -> PathBuf {
+	let root = std::env::temp_dir().join(format!("kabina-{}-{}", name, std::process::id()));
+	let _ = std::fs::remove_dir_all(&root);
+	std::fs::create_dir_all(&root).unwrap();
+	root
+}
@@ -1,10 +1,15 @@
 use std::path::PathBuf;
 
 use kabina_db::{
-	self, FileGroup, FileGroupItem, FileGroupStategy, ResolveRootFiles, RuntimeTask, Schema,
+	self, Cause, DiagnosticSeverity, DiagnosticSource, Diagnostics, FileChange, FileGroup,
+	FileGroupItem, FileGroupStategy, ResolveRootFiles, RuntimeTask, Span,
 };
 use url::Url;
 
+mod common;
+
+use common::{schema_with, temp_root};
+
 #[test]
 fn test() {
 	let mut db = kabina_db::Database::new();
@@ -17,18 +22,11 @@ fn test() {
 			strategy: FileGroupStategy::Time,
 			pattern: String::from("**/*"),
 		}],
+		true,
+		None,
 	);
 
-	let schema = Schema::new(
-		&db,
-		Url::from_file_path("/test").unwrap(),
-		[files].into_iter().collect(),
-		Default::default(),
-		Default::default(),
-		Default::default(),
-		Default::default(),
-		Default::default(),
-	);
+	let schema = schema_with(&db, &[files], &[]);
 
 	let _ = kabina_db::file_group_files(&db, schema, files);
 	let tasks = kabina_db::file_group_files::accumulated::<RuntimeTask>(&db, schema, files);
@@ -62,3 +60,233 @@ fn test() {
 	let tasks = kabina_db::file_group_files::accumulated::<RuntimeTask>(&db, schema, files);
 	assert_eq!(tasks.len(), 1);
 }
+
+#[test]
+fn test_root_files_apply() {
+	let root = temp_root("fileset");
+	std::fs::write(root.join("a.txt"), "a").unwrap();
+
+	let mut db = kabina_db::Database::new();
+
+	let files = FileGroup::new(
+		&db,
+		String::from("Test"),
+		root.clone(),
+		vec![FileGroupItem {
+			strategy: FileGroupStategy::Hash,
+			pattern: String::from("*.txt"),
+		}],
+		true,
+		None,
+	);
+
+	let schema = schema_with(&db, &[files], &[]);
+
+	let _ = kabina_db::file_group_files(&db, schema, files);
+	let tasks = kabina_db::file_group_files::accumulated::<RuntimeTask>(&db, schema, files);
+	for task in tasks {
+		if let Some(task) = task.downcast_ref::<ResolveRootFiles>() {
+			task.resolve(&mut db);
+		}
+	}
+
+	let result = kabina_db::file_group_files(&db, schema, files).unwrap();
+	assert_eq!(result.len(), 1);
+	let revision = result[0].revision(&db);
+
+	// Same content keeps the revision
+	std::fs::write(root.join("a.txt"), "a").unwrap();
+	kabina_db::root_files_apply(
+		&mut db,
+		schema,
+		&root,
+		&[FileChange::Modified(root.join("a.txt"))],
+	);
+	assert_eq!(result[0].revision(&db), revision);
+
+	std::fs::write(root.join("a.txt"), "changed").unwrap();
+	std::fs::write(root.join("b.txt"), "b").unwrap();
+	kabina_db::root_files_apply(
+		&mut db,
+		schema,
+		&root,
+		&[
+			FileChange::Modified(root.join("a.txt")),
+			FileChange::Created(root.join("b.txt")),
+		],
+	);
+	assert_ne!(result[0].revision(&db), revision);
+
+	let result = kabina_db::file_group_files(&db, schema, files).unwrap();
+	assert_eq!(result.len(), 2);
+
+	std::fs::remove_file(root.join("a.txt")).unwrap();
+	kabina_db::root_files_apply(
+		&mut db,
+		schema,
+		&root,
+		&[FileChange::Removed(root.join("a.txt"))],
+	);
+
+	let result = kabina_db::file_group_files(&db, schema, files).unwrap();
+	assert_eq!(result.len(), 1);
+	assert_eq!(result[0].path(&db), root.join("b.txt"));
+
+	let _ = std::fs::remove_dir_all(&root);
+}
+
+#[test]
+fn test_ignore_rules() {
+	let root = temp_root("ignore");
+	std::fs::create_dir_all(root.join("node_modules/pkg")).unwrap();
+	std::fs::create_dir_all(root.join("build")).unwrap();
+	std::fs::create_dir_all(root.join("src")).unwrap();
+	std::fs::write(root.join(".gitignore"), "build/\n").unwrap();
+	std::fs::write(root.join("node_modules/pkg/index.js"), "").unwrap();
+	std::fs::write(root.join("build/index.js"), "").unwrap();
+	std::fs::write(root.join("src/index.js"), "").unwrap();
+	std::fs::write(root.join("src/index.test.js"), "").unwrap();
+
+	let mut db = kabina_db::Database::new();
+
+	let items = || {
+		["**/*.js", "!**/*.test.js", "!node_modules"]
+			.into_iter()
+			.map(|pattern| FileGroupItem {
+				strategy: FileGroupStategy::Time,
+				pattern: String::from(pattern),
+			})
+			.collect::<Vec<_>>()
+	};
+
+	let ignoring = FileGroup::new(
+		&db,
+		String::from("Ignoring"),
+		root.clone(),
+		items(),
+		true,
+		None,
+	);
+	let all = FileGroup::new(&db, String::from("All"), root.clone(), items(), false, None);
+
+	let schema = schema_with(&db, &[ignoring, all], &[]);
+
+	let _ = kabina_db::file_group_files(&db, schema, ignoring);
+	let tasks = kabina_db::file_group_files::accumulated::<RuntimeTask>(&db, schema, ignoring);
+	for task in tasks {
+		if let Some(task) = task.downcast_ref::<ResolveRootFiles>() {
+			task.resolve(&mut db);
+		}
+	}
+
+	let paths = |group: FileGroup| {
+		let mut paths = kabina_db::file_group_files(&db, schema, group)
+			.unwrap()
+			.iter()
+			.map(|f| f.path(&db))
+			.collect::<Vec<_>>();
+		paths.sort();
+		paths
+	};
+
+	assert_eq!(paths(ignoring), vec![root.join("src/index.js")]);
+
+	// Opting out of ignore files picks up the ignored directory
+	assert_eq!(
+		paths(all),
+		vec![root.join("build/index.js"), root.join("src/index.js")]
+	);
+
+	let _ = std::fs::remove_dir_all(&root);
+}
+
+#[test]
+fn test_file_group_errors() {
+	let mut db = kabina_db::Database::new();
+
+	let files = FileGroup::new(
+		&db,
+		String::from("Test"),
+		PathBuf::from("/missing"),
+		vec![FileGroupItem {
+			strategy: FileGroupStategy::Time,
+			pattern: String::from("**/*"),
+		}],
+		true,
+		None,
+	);
+
+	let schema = schema_with(&db, &[files], &[]);
+
+	let _ = kabina_db::file_group_files(&db, schema, files);
+	let tasks = kabina_db::file_group_files::accumulated::<RuntimeTask>(&db, schema, files);
+	for task in tasks {
+		if let Some(task) = task.downcast_ref::<ResolveRootFiles>() {
+			// Files that can not be read are left out by the walk
+			task.resolve(&mut db);
+		}
+	}
+
+	let result = kabina_db::file_group_files(&db, schema, files);
+	assert_eq!(result.map(|files| files.len()).ok(), Some(0));
+
+	files.set_items(&mut db).to(vec![FileGroupItem {
+		strategy: FileGroupStategy::Time,
+		pattern: String::from("src/["),
+	}]);
+
+	let Err(Cause::Error(e)) = kabina_db::file_group_files(&db, schema, files) else {
+		panic!("Invalid pattern is not reported");
+	};
+	let message = format!("{:#}", e.0);
+	assert!(
+		message.starts_with("File group \"Test\": error parsing glob"),
+		"{}",
+		message
+	);
+}
+
+#[test]
+fn test_file_group_diagnostics() {
+	let mut db = kabina_db::Database::new();
+
+	let module = Url::from_file_path("/test/kabina.ts").unwrap();
+	let span = Span {
+		module: module.clone(),
+		line: 3,
+		column: 15,
+	};
+
+	let files = FileGroup::new(
+		&db,
+		String::from("Empty"),
+		PathBuf::from("/empty"),
+		vec![FileGroupItem {
+			strategy: FileGroupStategy::Time,
+			pattern: String::from("*.txt"),
+		}],
+		true,
+		Some(span.clone()),
+	);
+
+	let schema = schema_with(&db, &[files], &[]);
+
+	let _ = kabina_db::file_group_files(&db, schema, files);
+	let tasks = kabina_db::file_group_files::accumulated::<RuntimeTask>(&db, schema, files);
+	for task in tasks {
+		if let Some(task) = task.downcast_ref::<ResolveRootFiles>() {
+			task.apply(
+				&mut db,
+				task.matchers.keys().map(|k| (*k, Vec::new())).collect(),
+			);
+		}
+	}
+
+	assert!(kabina_db::file_group_files(&db, schema, files).is_ok());
+
+	let diagnostics = kabina_db::file_group_files::accumulated::<Diagnostics>(&db, schema, files);
+	assert_eq!(diagnostics.len(), 1);
+	assert_eq!(diagnostics[0].severity, DiagnosticSeverity::Warning);
+	assert_eq!(diagnostics[0].source, DiagnosticSource::FileGroup(files));
+	assert_eq!(diagnostics[0].span, Some(span));
+}
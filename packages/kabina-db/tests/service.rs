@@ -0,0 +1,92 @@
+use std::collections::BTreeMap;
+
+use kabina_db::{
+	self, Binary, BinaryNative, BinaryRuntime, Port, RestartPolicy, Service, ServiceCycle,
+};
+
+mod common;
+
+use common::schema_with;
+
+fn service(db: &kabina_db::Database, name: &str, depends_on: Vec<Service>) -> Service {
+	let binary = Binary::new(
+		db,
+		name.to_owned(),
+		BinaryRuntime::Native(BinaryNative {
+			executable: String::from("true"),
+			env: Default::default(),
+			args: Default::default(),
+			cwd: None,
+			env_files: Vec::new(),
+			inherit_env: true,
+		}),
+	);
+
+	Service::new(
+		db,
+		name.to_owned(),
+		binary,
+		RestartPolicy::Never,
+		Default::default(),
+		None,
+		depends_on,
+		Vec::new(),
+		None,
+		5000,
+		Default::default(),
+	)
+}
+
+#[test]
+fn test_services_start_order() {
+	let db = kabina_db::Database::new();
+
+	let database = service(&db, "database", vec![]);
+	let api = service(&db, "api", vec![database]);
+	let web = service(&db, "web", vec![api, database]);
+	let cache = service(&db, "cache", vec![]);
+
+	let schema = schema_with(&db, &[], &[web, cache, api, database]);
+	let order = kabina_db::services_start_order(&db, schema).unwrap();
+
+	assert_eq!(order, vec![database, api, cache, web]);
+}
+
+#[test]
+fn test_services_cycle() {
+	let mut db = kabina_db::Database::new();
+
+	let a = service(&db, "a", vec![]);
+	let b = service(&db, "b", vec![a]);
+	let c = service(&db, "c", vec![b]);
+	a.set_depends_on(&mut db).to(vec![c]);
+
+	let schema = schema_with(&db, &[], &[a, b, c]);
+	let cycle = kabina_db::services_start_order(&db, schema).unwrap_err();
+
+	assert_eq!(
+		cycle,
+		ServiceCycle(vec!["a".into(), "c".into(), "b".into(), "a".into()])
+	);
+}
+
+#[test]
+fn test_service_default_port() {
+	let mut db = kabina_db::Database::new();
+
+	let api = service(&db, "api", vec![]);
+	assert_eq!(api.default_port(&db), None);
+
+	let mut ports = BTreeMap::from([("admin".to_owned(), Port::Auto)]);
+	api.set_ports(&mut db).to(ports.clone());
+	assert_eq!(api.default_port(&db), None);
+
+	ports.insert("admin".into(), Port::Fixed(9000));
+	api.set_ports(&mut db).to(ports.clone());
+	assert_eq!(api.default_port(&db), Some(9000));
+
+	ports.insert("http".into(), Port::Fixed(8000));
+	api.set_ports(&mut db).to(ports);
+	assert_eq!(api.default_port(&db), Some(8000));
+	assert_eq!(api.fixed_ports(&db).len(), 2);
+}
@@ -1,14 +1,228 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
 use url::Url;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ServiceState {
+	/// Running the build step
+	Building,
+	/// The build step failed, waiting for the inputs to change
+	BuildFailed,
+	/// Running, but the readiness probe has not succeeded yet
+	Starting,
+	Ready,
+	/// The readiness probe keeps failing
+	Unhealthy,
+	/// Waiting before the next restart attempt
+	Backoff,
+	Stopped,
+	Exited { code: Option<i32> },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServiceInfo {
+	pub name: String,
+	pub pid: Option<u32>,
+	/// Seconds since the running process was spawned
+	pub uptime: Option<u64>,
+	pub state: ServiceState,
+	pub restart: String,
+	pub restarts: u32,
+	/// Ports of the service by name, including the allocated ones
+	pub ports: BTreeMap<String, u16>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerInfo {
+	pub name: String,
+	pub url: String,
+}
+
+/// State of a node of the schema graph, nodes are only computed when something needs them
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NodeState {
+	Pending,
+	Ok,
+	Error(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeStatus {
+	pub name: String,
+	pub state: NodeState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Severity {
+	Info,
+	Warning,
+	Error,
+}
+
+/// Problem found in a schema or while running it
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Diagnostic {
+	pub severity: Severity,
+	pub message: String,
+	/// Object of the schema, like `file group "src"`
+	pub source: Option<String>,
+	/// `path:line:column` of the object declaration in the schema module
+	pub location: Option<String>,
+}
+
+impl Diagnostic {
+	/// Diagnostic that is not about a particular object of the schema
+	pub fn message(severity: Severity, message: impl Into<String>) -> Self {
+		Diagnostic {
+			severity,
+			message: message.into(),
+			source: None,
+			location: None,
+		}
+	}
+}
+
+impl fmt::Display for Diagnostic {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let severity = match self.severity {
+			Severity::Info => "info",
+			Severity::Warning => "warning",
+			Severity::Error => "error",
+		};
+
+		write!(f, "{}: ", severity)?;
+		if let Some(source) = &self.source {
+			write!(f, "{}: ", source)?;
+		}
+		f.write_str(&self.message)?;
+		if let Some(location) = &self.location {
+			write!(f, "\n  --> {}", location)?;
+		}
+
+		Ok(())
+	}
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SchemaStatus {
+	pub url: Url,
+	pub file_groups: Vec<NodeStatus>,
+	pub transforms: Vec<NodeStatus>,
+	pub collections: Vec<NodeStatus>,
+	/// Services with the state of their binary, processes are reported separately
+	pub services: Vec<NodeStatus>,
+	/// Reported by the nodes computed so far
+	pub diagnostics: Vec<Diagnostic>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DaemonStatus {
+	pub version: String,
+	pub schemas: Vec<SchemaStatus>,
+	pub processes: Vec<ServiceInfo>,
+	pub servers: Vec<ServerInfo>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LogStream {
+	Stdout,
+	Stderr,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogLine {
+	pub service: String,
+	pub stream: LogStream,
+	pub line: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TaskKind {
+	WalkFiles,
+	Transform,
+	ResolveBinary,
+}
+
+/// Progress of the daemon, sent to every connected client
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Event {
+	TaskStarted {
+		id: u64,
+		kind: TaskKind,
+		name: String,
+	},
+	/// Duration is in milliseconds
+	TaskFinished { id: u64, duration: u64 },
+	TaskFailed {
+		id: u64,
+		duration: u64,
+		error: String,
+	},
+	FilesWalked { root: String, files: usize },
+	/// Transform output restored from the content store instead of running the transform
+	CacheHit { path: String },
+	ServiceState {
+		service: String,
+		state: ServiceState,
+	},
+	Diagnostic(Diagnostic),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, thiserror::Error)]
+pub enum ServiceError {
+	#[error("Service {0:?} is not found")]
+	NotFound(String),
+	#[error("Service name {0:?} is ambiguous, it is defined by several schemas")]
+	Ambiguous(String),
+}
+
+/// Query of the schema graph that failed while the daemon was driving it
+#[derive(Debug, Clone, Serialize, Deserialize, thiserror::Error)]
+#[error("{query} failed: {}", .causes.join(": "))]
+pub struct QueryError {
+	pub query: String,
+	/// The error and its causes, outermost first. They name the nodes the error went
+	/// through, like file groups and transforms, down to the underlying io error.
+	pub causes: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, thiserror::Error)]
+pub enum RunError {
+	#[error("Run is cancelled")]
+	Cancelled,
+	#[error("{} queries of the schema failed", .0.len())]
+	Failed(Vec<QueryError>),
+	#[error("Run stopped unexpectedly: {0}")]
+	Aborted(String),
+	#[error("Run {0} is already in progress")]
+	Duplicate(u64),
+}
+
 #[tarpc::service]
 pub trait Kabina {
 	async fn hello(name: String) -> String;
 	async fn version() -> String;
-	async fn schema_run(url: Url);
+	async fn status() -> DaemonStatus;
+	/// Loads the schema and starts its services, `run` is picked by the client to cancel it
+	/// and must not be used by another run in progress. Services whose queries fail are
+	/// skipped and the failures are returned.
+	async fn schema_run(url: Url, run: u64) -> Result<(), RunError>;
+	/// Stops pending work of the run between tasks, returns false if the run is not known
+	async fn cancel(run: u64, stop_services: bool) -> bool;
+	async fn service_list() -> Vec<ServiceInfo>;
+	async fn server_list() -> Vec<ServerInfo>;
+	async fn service_stop(name: String) -> Result<(), ServiceError>;
+	async fn service_restart(name: String) -> Result<(), ServiceError>;
+	/// Sends recent output of the service to the observer, then new lines if `follow` is set
+	async fn service_logs(name: String, follow: bool) -> Result<(), ServiceError>;
 	async fn terminate();
 }
 
 #[tarpc::service]
 pub trait KabinaObserver {
 	async fn log(name: String);
+	async fn service_log(line: LogLine);
+	async fn event(event: Event);
 }
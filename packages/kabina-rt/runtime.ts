@@ -18,12 +18,12 @@ import type {
 declare interface Deno {
   core: {
     ops: {
-      file_group: (cfg: FileGroupConfig) => number;
+      file_group: (cfg: FileGroupConfig & Located) => number;
       transform: (cfg: TransformConfigRuntime) => number;
-      collection: (cfg: CollectionConfig) => number;
+      collection: (cfg: CollectionConfig & Located) => number;
       server: (cfg: ServerConfig) => number;
-      service: (cfg: ServiceConfig) => number;
-      binary: (cfg: BinaryConfig) => number;
+      service: (cfg: ServiceConfigRuntime) => number;
+      binary: (cfg: BinaryConfig & Located & { module?: string }) => number;
     };
   };
 }
@@ -44,6 +44,29 @@ export default function caller(this: Bind | any, levelUp = up) {
   }
 }
 
+interface Location {
+  module: string;
+  line: number;
+  column: number;
+}
+
+interface Located {
+  location?: Location;
+}
+
+/** Position of the call to the config function in the schema module */
+export function callerLocation(levelUp = up): Location | undefined {
+  const stack = new Error().stack?.split("\n")[levelUp];
+  const match = stack?.match(/(file:\/\/[^(]*?):(\d+):(\d+)\)?$/);
+  if (match) {
+    return {
+      module: match[1],
+      line: Number(match[2]),
+      column: Number(match[3]),
+    };
+  }
+}
+
 export function getFile(this: Bind | any, stack: string) {
   stack = stack.substr(stack.indexOf("at ") + 3);
   if (!stack.startsWith("file://")) {
@@ -63,7 +86,10 @@ export const fileGroup: typeof FileGroupFunc = (
   fileGroupConfig: FileGroupConfig & { module?: string },
 ) => {
   fileGroupConfig.module = caller();
-  const id: number = Deno.core.ops.file_group(fileGroupConfig);
+  const id: number = Deno.core.ops.file_group({
+    ...fileGroupConfig,
+    location: callerLocation(),
+  });
   return {
     kind: "FileGroup",
     id,
@@ -73,7 +99,7 @@ export const fileGroup: typeof FileGroupFunc = (
 // deno-lint-ignore no-explicit-any
 type Dependency = FileGroup | Transform<any>;
 
-interface TransformConfigRuntime {
+interface TransformConfigRuntime extends Located {
   name: string;
   module: string | undefined;
   runner: number;
@@ -81,6 +107,7 @@ interface TransformConfigRuntime {
   input: any;
   // deno-lint-ignore no-explicit-any
   dependencies: any;
+  source: string;
 }
 
 // deno-lint-ignore ban-types
@@ -96,9 +123,11 @@ export const transform: typeof TransformFunc = <I, D, O>(
   const config: TransformConfigRuntime = {
     name: transformConfig.name,
     module: caller(),
+    location: callerLocation(),
     input: transformConfig.input,
     dependencies: transformConfig.dependencies || null,
     runner: 0,
+    source: transformConfig.run.toString(),
   };
 
   const id: number = Deno.core.ops.transform(config);
@@ -112,7 +141,10 @@ export const transform: typeof TransformFunc = <I, D, O>(
 };
 
 export const collection: typeof CollectionFunc = (config: CollectionConfig) => {
-  const id: number = Deno.core.ops.collection(config);
+  const id: number = Deno.core.ops.collection({
+    ...config,
+    location: callerLocation(),
+  });
 
   return {
     kind: "Collection",
@@ -121,7 +153,10 @@ export const collection: typeof CollectionFunc = (config: CollectionConfig) => {
 };
 
 export const server: typeof ServerFunc = (config: ServerConfig) => {
-  const id: number = Deno.core.ops.server(config);
+  const id: number = Deno.core.ops.server({
+    ...config,
+    location: callerLocation(),
+  });
 
   return {
     kind: "Server",
@@ -130,7 +165,11 @@ export const server: typeof ServerFunc = (config: ServerConfig) => {
 };
 
 export const binary: typeof BinaryFunc = (config: BinaryConfig) => {
-  const id: number = Deno.core.ops.binary(config);
+  const id: number = Deno.core.ops.binary({
+    ...config,
+    module: caller(),
+    location: callerLocation(),
+  });
 
   return {
     kind: "Binary",
@@ -138,8 +177,26 @@ export const binary: typeof BinaryFunc = (config: BinaryConfig) => {
   };
 };
 
+interface ServiceConfigRuntime extends ServiceConfig, Located {
+  stdoutCallback: boolean;
+}
+
+// deno-lint-ignore no-explicit-any
+const processLogs: { [key: number]: (line: any) => void } = {};
+
+export const __processLogs = processLogs;
+
 export const service: typeof ServiceFunc = (config: ServiceConfig) => {
-  const id: number = Deno.core.ops.service(config);
+  const stdout = config.processLogs?.stdout;
+  const id: number = Deno.core.ops.service({
+    ...config,
+    stdoutCallback: stdout !== undefined,
+    location: callerLocation(),
+  });
+
+  if (stdout) {
+    processLogs[id] = stdout;
+  }
 
   return {
     kind: "Service",
@@ -1,13 +1,21 @@
 use std::collections::BTreeMap;
+use std::sync::Arc;
 
 use deno_core::{op, OpState};
-use kabina_db::{Binary, BinaryNative, BinaryRuntime, SharedDatabase};
+use kabina_db::{
+	binary_file_revision, Binary, BinaryNative, BinaryRuntime, SchemaBuilder, SharedDatabase, Span,
+};
 use serde::Deserialize;
 
+use crate::validate::name_validate;
+use crate::JsLocation;
+
 #[derive(Deserialize)]
 pub struct JsBinary {
 	pub name: String,
+	pub module: deno_core::url::Url,
 	pub runtime: JsBinaryRuntime,
+	pub location: Option<JsLocation>,
 }
 
 #[derive(Deserialize)]
@@ -18,34 +26,82 @@ pub enum JsBinaryRuntime {
 }
 
 #[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct JsBinaryNative {
 	pub executable: String,
 	#[serde(default)]
 	pub env: BTreeMap<String, String>,
-	#[serde(default)]
+	#[serde(default, alias = "arguments")]
 	pub args: Vec<String>,
+	pub cwd: Option<String>,
+	#[serde(default)]
+	pub env_file: JsEnvFiles,
+	pub inherit_env: Option<bool>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum JsEnvFiles {
+	One(String),
+	Many(Vec<String>),
+}
+
+impl Default for JsEnvFiles {
+	fn default() -> Self {
+		JsEnvFiles::Many(Vec::new())
+	}
+}
+
+impl JsEnvFiles {
+	fn into_vec(self) -> Vec<String> {
+		match self {
+			JsEnvFiles::One(file) => vec![file],
+			JsEnvFiles::Many(files) => files,
+		}
+	}
 }
 
 #[op]
 pub fn binary(state: &mut OpState, b: JsBinary) -> Result<f64, deno_core::error::AnyError> {
-	tracing::info!("Binary {:?} created", b.name);
+	let mut module_root = b.module.to_file_path().unwrap();
+	if module_root.extension().is_some() {
+		module_root = module_root.parent().unwrap().to_owned()
+	}
 
 	let db = state.borrow::<SharedDatabase>();
-	// let schema = state.borrow::<Arc<SchemaBuilder>>();
+	let schema = state.borrow::<Arc<SchemaBuilder>>();
+
+	let span = b.location.map(Span::from);
+	name_validate(schema, "Binary", &b.name, span.as_ref())?;
+
+	tracing::info!("Binary {:?} created", b.name);
+
+	let JsBinaryRuntime::Native(native) = b.runtime;
+	let env_files = native
+		.env_file
+		.into_vec()
+		.into_iter()
+		.map(|f| {
+			let path = module_root.join(f);
+			let revision = binary_file_revision(&path);
+			db.lock().file_update(path, revision)
+		})
+		.collect();
 
 	let handle = Binary::new(
 		&*db.lock(),
 		b.name,
-		match b.runtime {
-			JsBinaryRuntime::Native(b) => BinaryRuntime::Native(BinaryNative {
-				executable: b.executable,
-				env: b.env,
-				args: b.args,
-			}),
-		},
+		BinaryRuntime::Native(BinaryNative {
+			executable: native.executable,
+			env: native.env,
+			args: native.args,
+			cwd: native.cwd.map(|cwd| module_root.join(cwd)),
+			env_files,
+			inherit_env: native.inherit_env.unwrap_or(true),
+		}),
 	);
 
-	// schema.register_transform(handle);
+	schema.register_binary(handle);
 
 	Ok(usize::from(kabina_db::AsId::as_id(handle)) as f64)
 }
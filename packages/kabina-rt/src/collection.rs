@@ -2,10 +2,12 @@ use std::path::PathBuf;
 use std::sync::Arc;
 
 use deno_core::{op, OpState};
-use kabina_db::{Collection, CollectionItem, SchemaBuilder, SharedDatabase};
+use kabina_db::{Collection, CollectionItem, SchemaBuilder, SharedDatabase, Span};
 use serde::Deserialize;
 
 use crate::transform::{map_js_dep, JsDependency};
+use crate::validate::{config_error, name_validate, reference_validate};
+use crate::JsLocation;
 
 #[derive(Deserialize)]
 pub struct JsCollectionItem {
@@ -17,28 +19,35 @@ pub struct JsCollectionItem {
 pub struct JsCollection {
 	name: String,
 	items: Vec<JsCollectionItem>,
+	location: Option<JsLocation>,
 }
 
 #[op]
 pub fn collection(state: &mut OpState, b: JsCollection) -> Result<f64, deno_core::error::AnyError> {
-	tracing::info!("Collection {:?} created ", b.name);
-
 	let db = state.borrow::<SharedDatabase>();
 	let schema = state.borrow::<Arc<SchemaBuilder>>();
 
-	let handle = Collection::new(
-		&*db.lock(),
-		b.name,
-		b.items
-			.into_iter()
-			.map(|i| CollectionItem {
-				prefix: i.prefix,
-				content: map_js_dep(i.content)
-					.to_input_kind()
-					.expect("Input dependency"),
-			})
-			.collect(),
-	);
+	let span = b.location.map(Span::from);
+	name_validate(schema, "Collection", &b.name, span.as_ref())?;
+
+	let mut items = Vec::new();
+	for i in b.items {
+		let dep = map_js_dep(i.content);
+		reference_validate(schema, dep, span.as_ref())?;
+
+		let Some(content) = dep.to_input_kind() else {
+			let message = format!("Collection {:?} can only contain files", b.name);
+			return Err(config_error(span.as_ref(), message));
+		};
+		items.push(CollectionItem {
+			prefix: i.prefix,
+			content,
+		});
+	}
+
+	tracing::info!("Collection {:?} created ", b.name);
+
+	let handle = Collection::new(&*db.lock(), b.name, items, span);
 
 	schema.register_collection(handle);
 
@@ -1,17 +1,24 @@
 use std::sync::Arc;
 
 use deno_core::{op, OpState};
-use kabina_db::{SchemaBuilder, SharedDatabase};
+use kabina_db::{file_group_pattern_validate, SchemaBuilder, SharedDatabase, Span};
 use serde::Deserialize;
 
+use crate::validate::{config_error, name_validate};
+use crate::JsLocation;
+
 #[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum JsFileGroupStategy {
+	#[serde(alias = "Hash")]
 	Hash,
+	#[serde(alias = "Time")]
 	Time,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct JsFileGroupItem {
+	#[serde(alias = "version")]
 	strategy: Option<JsFileGroupStategy>,
 	pattern: String,
 }
@@ -29,6 +36,10 @@ pub struct JsFileGroup {
 	module: deno_core::url::Url,
 	root: Option<String>,
 	items: Vec<JsFileGroupItemShortcut>,
+	#[serde(default)]
+	exclude: Vec<String>,
+	gitignore: Option<bool>,
+	location: Option<JsLocation>,
 }
 
 #[op]
@@ -44,11 +55,25 @@ pub fn file_group(state: &mut OpState, f: JsFileGroup) -> Result<f64, deno_core:
 		module_root
 	};
 
-	tracing::info!("File group {:?} created at {:?}", f.name, root.to_str());
-
 	let db = state.borrow::<SharedDatabase>();
 	let schema = state.borrow::<Arc<SchemaBuilder>>();
 
+	let span = f.location.map(Span::from);
+	name_validate(schema, "File group", &f.name, span.as_ref())?;
+
+	let patterns = f.items.iter().map(|i| match i {
+		JsFileGroupItemShortcut::String(s) => s,
+		JsFileGroupItemShortcut::Item(i) => &i.pattern,
+	});
+	for pattern in patterns.chain(&f.exclude) {
+		if let Err(e) = file_group_pattern_validate(pattern) {
+			let message = format!("File group {:?} has an invalid pattern: {}", f.name, e);
+			return Err(config_error(span.as_ref(), message));
+		}
+	}
+
+	tracing::info!("File group {:?} created at {:?}", f.name, root.to_str());
+
 	let handle = kabina_db::FileGroup::new(
 		&*db.lock(),
 		f.name,
@@ -69,7 +94,13 @@ pub fn file_group(state: &mut OpState, f: JsFileGroup) -> Result<f64, deno_core:
 					pattern: i.pattern,
 				},
 			})
+			.chain(f.exclude.into_iter().map(|e| kabina_db::FileGroupItem {
+				pattern: format!("!{}", e.trim_start_matches('!')),
+				strategy: kabina_db::FileGroupStategy::Time,
+			}))
 			.collect(),
+		f.gitignore.unwrap_or(true),
+		span,
 	);
 
 	schema.register_file_group(handle);
@@ -1,15 +1,21 @@
 #![feature(async_fn_in_trait)]
 
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 use std::rc::Rc;
 use std::sync::Arc;
 
+use anyhow::{anyhow, bail, Context};
 use deno_core::url::Url;
 use deno_core::v8::{HandleScope, Local};
-use deno_core::{v8, Extension, JsRuntime, ModuleLoader, RuntimeOptions};
+use deno_core::{v8, Extension, JsRuntime, ModuleLoader, RuntimeOptions, ZeroCopyBuf};
 use kabina_db::runtime::Runtime;
-use kabina_db::{AsId, Schema, SchemaBuilder, SharedDatabase};
+use kabina_db::{
+	transform_identity, AsId, Cause, File, Outcome, Schema, SchemaBuilder, Service, SharedDatabase,
+	Span, TransformApply,
+};
 use module::KabinaModuleLoader;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 mod binary;
 mod collection;
@@ -18,6 +24,7 @@ mod module;
 mod server;
 mod service;
 mod transform;
+mod validate;
 
 pub struct DenoRuntime {
 	db: SharedDatabase,
@@ -70,14 +77,227 @@ impl DenoRuntime {
 
 		DenoRuntime { db, runtime, std }
 	}
+
+	/// Ids of the schema transform functions by transform identity. Transforms sharing an
+	/// identity are left out, there is no telling which function to call for them, so they
+	/// are only run by the runtime which declared them.
+	pub fn transform_functions(&self, schema: Schema) -> HashMap<String, u32> {
+		let db = self.db.lock();
+		let mut functions = HashMap::new();
+		let mut duplicates = HashSet::new();
+
+		for transform in schema.transforms(&*db).iter() {
+			let identity = transform_identity(&*db, *transform);
+			let id = AsId::as_id(*transform).as_u32();
+			if functions.insert(identity.clone(), id).is_some() {
+				duplicates.insert(identity);
+			}
+		}
+
+		for identity in duplicates.iter() {
+			let message = "only the runtime of the schema runs them";
+			tracing::warn!("Transforms share the identity {}, {}", identity, message);
+		}
+
+		functions.retain(|identity, _| !duplicates.contains(identity));
+		functions
+	}
+
+	/// Runs the transform function `id` of this runtime on a task of another database,
+	/// the input is read from and the output is written to `db`. The function comes from a
+	/// separate evaluation of the schema module, so it only gives the same result as the one
+	/// the task was created for when transforms are pure and keep no module state.
+	pub async fn transform_function(
+		&mut self,
+		id: u32,
+		task: &TransformApply,
+		db: &SharedDatabase,
+	) -> Outcome<File> {
+		self.call_transform(id, task, db)
+			.await
+			.map_err(Cause::from_anyhow)
+	}
+
+	async fn call_transform(
+		&mut self,
+		id: u32,
+		task: &TransformApply,
+		db: &SharedDatabase,
+	) -> Result<File, anyhow::Error> {
+		let file_path = task.file.path(&*db.lock());
+		let content = tokio::fs::read(&file_path).await?;
+
+		#[derive(Serialize)]
+		#[allow(non_snake_case)]
+		struct Context {
+			filePath: String,
+			fileName: String,
+			/// Missing when the file is not valid UTF-8, `buffer` has the bytes then
+			content: Option<String>,
+			buffer: ZeroCopyBuf,
+		}
+
+		let text = std::str::from_utf8(&content).ok().map(str::to_owned);
+
+		let value = {
+			let ns = self.runtime.get_module_namespace(self.std)?;
+
+			let context = self.runtime.global_context();
+			let isolate = self.runtime.v8_isolate();
+
+			let ns = ns.open(isolate);
+			let mut scope = HandleScope::with_context(isolate, context);
+
+			let string = v8::String::new(&mut scope, "__transforms").unwrap();
+
+			let value = ns.get(&mut scope, string.into()).unwrap();
+			let js_id = v8::Number::new(&mut scope, id as f64);
+
+			let function = value
+				.to_object(&mut scope)
+				.unwrap()
+				.get(&mut scope, js_id.into())
+				.ok_or_else(|| anyhow!("Transform {} is not registered", id))?;
+
+			let function = Local::<v8::Function>::try_from(function)?;
+			let null = v8::null(&mut scope).into();
+
+			let deps_v8 = deno_core::serde_v8::to_v8(&mut scope, &*task.dependencies)?;
+			let context = deno_core::serde_v8::to_v8(
+				&mut scope,
+				Context {
+					filePath: file_path.to_string_lossy().to_string(),
+					fileName: task.file_name.to_string_lossy().to_string(),
+					content: text,
+					buffer: content.into(),
+				},
+			)?;
+
+			let scope = &mut v8::TryCatch::new(&mut scope);
+			match function.call(scope, null, &[context, deps_v8]) {
+				Some(value) => v8::Global::new(scope, value),
+				None => {
+					let message = scope
+						.exception()
+						.map(|e| e.to_rust_string_lossy(scope))
+						.unwrap_or_else(|| String::from("unknown exception"));
+					bail!("Transform {} failed on {:?}: {}", id, file_path, message)
+				}
+			}
+		};
+
+		// The transform may be an async function
+		let value = self.runtime.resolve_value(value).await?;
+
+		let output: JsTransformOutput = {
+			let scope = &mut self.runtime.handle_scope();
+			let value = Local::new(scope, value);
+			deno_core::serde_v8::from_v8(scope, value)?
+		};
+
+		let (file_name, content) = match output {
+			JsTransformOutput::Content(content) => (task.file_name.clone(), content),
+			JsTransformOutput::File { file_name, content } => (
+				file_name
+					.map(PathBuf::from)
+					.unwrap_or_else(|| task.file_name.clone()),
+				content,
+			),
+		};
+
+		let mut db = db.lock();
+		Ok(task.write(&mut *db, &file_name, content.as_bytes())?)
+	}
+
+	/// Passes a line of the service stdout to its `processLogs.stdout` callback
+	pub async fn process_log(
+		&mut self,
+		service: Service,
+		line: String,
+	) -> Result<(), anyhow::Error> {
+		let id = AsId::as_id(service).as_u32();
+
+		let value = {
+			let ns = self.runtime.get_module_namespace(self.std)?;
+
+			let context = self.runtime.global_context();
+			let isolate = self.runtime.v8_isolate();
+
+			let ns = ns.open(isolate);
+			let mut scope = HandleScope::with_context(isolate, context);
+
+			let string = v8::String::new(&mut scope, "__processLogs").unwrap();
+
+			let value = ns.get(&mut scope, string.into()).unwrap();
+			let js_id = v8::Number::new(&mut scope, id as f64);
+
+			let function = value
+				.to_object(&mut scope)
+				.unwrap()
+				.get(&mut scope, js_id.into())
+				.ok_or_else(|| anyhow!("Log callback of service {} is not registered", id))?;
+
+			let function = Local::<v8::Function>::try_from(function)?;
+			let null = v8::null(&mut scope).into();
+			let line = v8::String::new(&mut scope, &line).unwrap().into();
+
+			let scope = &mut v8::TryCatch::new(&mut scope);
+			match function.call(scope, null, &[line]) {
+				Some(value) => v8::Global::new(scope, value),
+				None => {
+					let message = scope
+						.exception()
+						.map(|e| e.to_rust_string_lossy(scope))
+						.unwrap_or_else(|| String::from("unknown exception"));
+					bail!("Log callback of service {} failed: {}", id, message)
+				}
+			}
+		};
+
+		// The callback may be an async function
+		self.runtime.resolve_value(value).await?;
+		Ok(())
+	}
+}
+
+/// Position of the config call, captured by `callerLocation` in `runtime.ts`
+#[derive(Deserialize)]
+pub struct JsLocation {
+	module: Url,
+	line: u32,
+	column: u32,
+}
+
+impl From<JsLocation> for Span {
+	fn from(l: JsLocation) -> Self {
+		Span {
+			module: l.module,
+			line: l.line,
+			column: l.column,
+		}
+	}
+}
+
+/// Value returned from a JS transform function
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsTransformOutput {
+	Content(String),
+	File {
+		#[serde(rename = "fileName")]
+		file_name: Option<String>,
+		content: String,
+	},
 }
 
 impl Runtime for DenoRuntime {
-	async fn load_schema(&mut self, url: Url) -> Schema {
+	async fn load_schema(&mut self, url: Url) -> Result<Schema, anyhow::Error> {
 		let schema = Arc::new(SchemaBuilder::default());
 		self.runtime.op_state().borrow_mut().put(schema);
 
-		let source = tokio::fs::read_to_string(url.path()).await.unwrap();
+		let source = tokio::fs::read_to_string(url.path())
+			.await
+			.with_context(|| format!("Failed to read schema module {}", url))?;
 		let module = self
 			.runtime
 			.load_main_module(
@@ -85,12 +305,14 @@ impl Runtime for DenoRuntime {
 				Some(deno_core::ModuleCode::Owned(source.into_boxed_str())),
 			)
 			.await
-			.unwrap();
+			.with_context(|| format!("Failed to load schema module {}", url))?;
 
 		let eval = self.runtime.mod_evaluate(module);
 
-		self.runtime.run_event_loop(false).await.unwrap();
-		eval.await.unwrap().unwrap();
+		let evaluated = match self.runtime.run_event_loop(false).await {
+			Ok(()) => eval.await.unwrap_or_else(|e| Err(e.into())),
+			Err(e) => Err(e),
+		};
 
 		let builder = self
 			.runtime
@@ -98,11 +320,13 @@ impl Runtime for DenoRuntime {
 			.borrow_mut()
 			.take::<Arc<SchemaBuilder>>();
 
+		evaluated.with_context(|| format!("Failed to evaluate schema module {}", url))?;
+
 		let Ok(builder) = Arc::try_unwrap(builder) else {
-            panic!("Arc cloned")
-        };
+			bail!("Schema builder of {} is still in use", url)
+		};
 
-		Schema::new(
+		Ok(Schema::new(
 			&*self.db.lock(),
 			url,
 			builder.file_groups,
@@ -111,57 +335,12 @@ impl Runtime for DenoRuntime {
 			builder.servers,
 			builder.services,
 			builder.binaries,
-		)
+		))
 	}
 
-	async fn transform(&mut self, task: &kabina_db::TransformApply) -> kabina_db::File {
+	async fn transform(&mut self, task: &TransformApply) -> Outcome<File> {
 		let id = AsId::as_id(task.transform).as_u32();
-
-		let ns = self.runtime.get_module_namespace(self.std).unwrap();
-
-		let context = self.runtime.global_context();
-		let isolate = self.runtime.v8_isolate();
-
-		let ns = ns.open(isolate);
-		let mut scope = HandleScope::with_context(isolate, context);
-
-		let string = v8::String::new(&mut scope, "__transforms").unwrap();
-
-		let value = ns.get(&mut scope, string.into()).unwrap();
-		let js_id = v8::Number::new(&mut scope, id as f64);
-
-		let function = value
-			.to_object(&mut scope)
-			.unwrap()
-			.get(&mut scope, js_id.into())
-			.unwrap();
-
-		let function = Local::<v8::Function>::try_from(function).unwrap();
-		let null = v8::null(&mut scope).into();
-
-		#[derive(Serialize)]
-		#[allow(non_snake_case)]
-		struct Context {
-			filePath: String,
-		}
-
-		let db = self.db.lock();
-
-		let deps_v8 = deno_core::serde_v8::to_v8(&mut scope, &*task.dependencies).unwrap();
-		let context = deno_core::serde_v8::to_v8(
-			&mut scope,
-			Context {
-				filePath: task.file.path(&*db).to_string_lossy().to_string(),
-			},
-		)
-		.unwrap();
-
-		let value = function
-			.call(&mut scope, null, &[context, deps_v8])
-			.unwrap();
-
-		println!("{:?}", value.is_boolean());
-
-		todo!();
+		let db = self.db.clone();
+		self.transform_function(id, task, &db).await
 	}
 }
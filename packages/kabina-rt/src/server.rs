@@ -1,12 +1,30 @@
+use std::collections::BTreeMap;
 use std::sync::Arc;
 
 use deno_core::{op, OpState};
-use kabina_db::{SchemaBuilder, Server, SharedDatabase};
+use kabina_db::{
+	server_routes_sort, AsId, Collection, SchemaBuilder, Server, ServerRoute, SharedDatabase, Span,
+};
 use serde::Deserialize;
 
+use crate::service::JsPort;
+use crate::validate::{name_validate, reference_validate, Reference};
+use crate::JsLocation;
+
+#[derive(Deserialize)]
+pub struct JsCollectionRef {
+	id: usize,
+}
+
 #[derive(Deserialize)]
 pub struct JsServer {
 	name: String,
+	port: Option<JsPort>,
+	#[serde(default)]
+	routes: BTreeMap<String, JsCollectionRef>,
+	#[serde(rename = "liveReload")]
+	live_reload: Option<bool>,
+	location: Option<JsLocation>,
 }
 
 #[op]
@@ -16,7 +34,25 @@ pub fn server(state: &mut OpState, s: JsServer) -> Result<f64, deno_core::error:
 	let db = state.borrow::<SharedDatabase>();
 	let schema = state.borrow::<Arc<SchemaBuilder>>();
 
-	let handle = Server::new(&*db.lock(), s.name);
+	let span = s.location.map(Span::from);
+	name_validate(schema, "Server", &s.name, span.as_ref())?;
+
+	let mut routes = Vec::new();
+	for (pattern, c) in s.routes {
+		let collection = Collection::from_id(c.id.into());
+		reference_validate(schema, Reference::Collection(collection), span.as_ref())?;
+		routes.push(ServerRoute::new(pattern, collection));
+	}
+
+	server_routes_sort(&mut routes);
+
+	let handle = Server::new(
+		&*db.lock(),
+		s.name,
+		s.port.map(Into::into),
+		routes,
+		s.live_reload.unwrap_or(true),
+	);
 
 	schema.register_server(handle);
 
@@ -1,24 +1,206 @@
+use std::collections::BTreeMap;
 use std::sync::Arc;
 
 use deno_core::{op, OpState};
-use kabina_db::{SchemaBuilder, Service, SharedDatabase};
+use kabina_db::{
+	AsId, Binary, Collection, FileGroup, Port, Readiness, ReadinessProbe, RestartPolicy,
+	SchemaBuilder, Service, ServiceInput, ServiceLogConfig, SharedDatabase, Span, Transform,
+};
 use serde::Deserialize;
 
+use crate::validate::{name_validate, reference_validate, Reference};
+use crate::JsLocation;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum JsRestartPolicy {
+	Never,
+	OnFailure,
+	Always,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum JsReadinessProbe {
+	Tcp {
+		host: Option<String>,
+		port: u16,
+	},
+	Http {
+		url: String,
+	},
+	Log {
+		pattern: String,
+	},
+	Exec {
+		command: String,
+		#[serde(default)]
+		arguments: Vec<String>,
+	},
+}
+
+#[derive(Deserialize)]
+pub struct JsReadiness {
+	#[serde(flatten)]
+	probe: JsReadinessProbe,
+	interval: Option<u64>,
+	#[serde(rename = "failureThreshold")]
+	failure_threshold: Option<u32>,
+}
+
+impl From<JsReadiness> for Readiness {
+	fn from(r: JsReadiness) -> Self {
+		let probe = match r.probe {
+			JsReadinessProbe::Tcp { host, port } => ReadinessProbe::Tcp {
+				host: host.unwrap_or_else(|| "127.0.0.1".into()),
+				port,
+			},
+			JsReadinessProbe::Http { url } => ReadinessProbe::Http { url },
+			JsReadinessProbe::Log { pattern } => ReadinessProbe::Log { pattern },
+			JsReadinessProbe::Exec { command, arguments } => ReadinessProbe::Exec {
+				command,
+				args: arguments,
+			},
+		};
+
+		Readiness {
+			probe,
+			interval: r.interval.unwrap_or(1000),
+			failure_threshold: r.failure_threshold.unwrap_or(3),
+		}
+	}
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JsPortAuto {
+	Auto,
+}
+
+/// Port number or `"auto"`
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum JsPort {
+	Fixed(u16),
+	Auto(JsPortAuto),
+}
+
+impl From<JsPort> for Port {
+	fn from(p: JsPort) -> Self {
+		match p {
+			JsPort::Fixed(port) => Port::Fixed(port),
+			JsPort::Auto(JsPortAuto::Auto) => Port::Auto,
+		}
+	}
+}
+
+#[derive(Deserialize)]
+pub struct JsServiceRef {
+	id: usize,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "kind")]
+pub enum JsServiceInput {
+	FileGroup { id: usize },
+	Transform { id: usize },
+	Collection { id: usize },
+}
+
+impl From<JsServiceInput> for ServiceInput {
+	fn from(i: JsServiceInput) -> Self {
+		match i {
+			JsServiceInput::FileGroup { id } => ServiceInput::FileGroup(FileGroup::from_id(id.into())),
+			JsServiceInput::Transform { id } => ServiceInput::Transform(Transform::from_id(id.into())),
+			JsServiceInput::Collection { id } => {
+				ServiceInput::Collection(Collection::from_id(id.into()))
+			}
+		}
+	}
+}
+
+#[derive(Deserialize)]
+pub struct JsBinaryRef {
+	id: usize,
+}
+
 #[derive(Deserialize)]
 pub struct JsService {
 	pub name: String,
 	pub binary: usize,
+	pub restart: Option<JsRestartPolicy>,
+	#[serde(rename = "logFile", default)]
+	pub log_file: bool,
+	/// Set by the runtime when `processLogs.stdout` is registered
+	#[serde(rename = "stdoutCallback", default)]
+	pub stdout_callback: bool,
+	pub readiness: Option<JsReadiness>,
+	#[serde(rename = "dependsOn", default)]
+	pub depends_on: Vec<JsServiceRef>,
+	#[serde(default)]
+	pub inputs: Vec<JsServiceInput>,
+	pub build: Option<JsBinaryRef>,
+	/// Milliseconds between SIGTERM and SIGKILL
+	#[serde(rename = "stopTimeout")]
+	pub stop_timeout: Option<u64>,
+	#[serde(default)]
+	pub ports: BTreeMap<String, JsPort>,
+	pub location: Option<JsLocation>,
 }
 
 #[op]
 pub fn service(state: &mut OpState, s: JsService) -> Result<f64, deno_core::error::AnyError> {
-	tracing::info!("Service {:?} created", s.name);
-
 	let db = state.borrow::<SharedDatabase>();
 	let schema = state.borrow::<Arc<SchemaBuilder>>();
 
-	let binary = kabina_db::AsId::from_id(s.binary.into());
-	let handle = Service::new(&*db.lock(), s.name, binary);
+	let span = s.location.map(Span::from);
+	name_validate(schema, "Service", &s.name, span.as_ref())?;
+
+	let binary = Binary::from_id(s.binary.into());
+	let depends_on: Vec<Service> = s
+		.depends_on
+		.into_iter()
+		.map(|d| Service::from_id(d.id.into()))
+		.collect();
+	let inputs: Vec<ServiceInput> = s.inputs.into_iter().map(ServiceInput::from).collect();
+	let build = s.build.map(|b| Binary::from_id(b.id.into()));
+
+	let references = depends_on
+		.iter()
+		.map(|d| Reference::Service(*d))
+		.chain(inputs.iter().map(|i| Reference::from(*i)))
+		.chain(build.map(Reference::Binary))
+		.chain([Reference::Binary(binary)]);
+	for reference in references {
+		reference_validate(schema, reference, span.as_ref())?;
+	}
+
+	tracing::info!("Service {:?} created", s.name);
+
+	let restart = match s.restart {
+		None | Some(JsRestartPolicy::Never) => RestartPolicy::Never,
+		Some(JsRestartPolicy::OnFailure) => RestartPolicy::OnFailure,
+		Some(JsRestartPolicy::Always) => RestartPolicy::Always,
+	};
+
+	let logs = ServiceLogConfig {
+		file: s.log_file,
+		stdout_callback: s.stdout_callback,
+	};
+
+	let handle = Service::new(
+		&*db.lock(),
+		s.name,
+		binary,
+		restart,
+		logs,
+		s.readiness.map(Readiness::from),
+		depends_on,
+		inputs,
+		build,
+		s.stop_timeout.unwrap_or(5000),
+		s.ports.into_iter().map(|(name, p)| (name, p.into())).collect(),
+	);
 
 	schema.register_service(handle);
 
@@ -2,10 +2,15 @@ use std::sync::Arc;
 
 use deno_core::serde_json::Value;
 use deno_core::{op, OpState};
-use kabina_db::deps::Dependency;
-use kabina_db::{AsId, Binary, FileGroup, RunnerKind, SchemaBuilder, SharedDatabase, Transform};
+use kabina_db::deps::{extract_dependencies, Dependency};
+use kabina_db::{
+	bytes_hash, AsId, Binary, FileGroup, RunnerKind, SchemaBuilder, SharedDatabase, Span, Transform,
+};
 use serde::Deserialize;
 
+use crate::validate::{name_validate, reference_validate};
+use crate::JsLocation;
+
 #[derive(Deserialize)]
 #[serde(tag = "kind")]
 pub enum JsDependency {
@@ -21,6 +26,9 @@ pub struct JsTransform {
 	runner: u64,
 	input: Value,
 	dependencies: Value,
+	/// Source of the transform function
+	source: Option<String>,
+	location: Option<JsLocation>,
 }
 
 pub fn map_js_dep(dep: JsDependency) -> Dependency {
@@ -38,17 +46,29 @@ pub fn transform(state: &mut OpState, f: JsTransform) -> Result<f64, deno_core::
 		root = root.parent().unwrap().to_owned()
 	}
 
-	tracing::info!("Transform {:?} created at {:?}", f.name, root.to_str());
-
 	let db = state.borrow::<SharedDatabase>();
 	let schema = state.borrow::<Arc<SchemaBuilder>>();
 
+	let span = f.location.map(Span::from);
+	name_validate(schema, "Transform", &f.name, span.as_ref())?;
+
+	let mut references = Vec::new();
+	extract_dependencies(&f.input, &mut references);
+	extract_dependencies(&f.dependencies, &mut references);
+	for dep in references {
+		reference_validate(schema, dep, span.as_ref())?;
+	}
+
+	tracing::info!("Transform {:?} created at {:?}", f.name, root.to_str());
+
 	let handle = Transform::new(
 		&*db.lock(),
 		f.name,
 		RunnerKind::JsFunction(f.runner),
 		f.input,
 		f.dependencies,
+		f.source.as_deref().map(str::as_bytes).map(bytes_hash).unwrap_or_default(),
+		span,
 	);
 
 	schema.register_transform(handle);
@@ -0,0 +1,87 @@
+use std::fmt::Display;
+
+use anyhow::anyhow;
+use deno_core::error::AnyError;
+use kabina_db::deps::Dependency;
+use kabina_db::{
+	AsId, Binary, Collection, FileGroup, SchemaBuilder, Service, ServiceInput, Span, Transform,
+};
+
+/// Error thrown back to the schema module, pointing at the config call that caused it
+pub fn config_error(span: Option<&Span>, message: impl Display) -> AnyError {
+	match span {
+		Some(span) => anyhow!("{}\n    at {}", message, span),
+		None => anyhow!("{}", message),
+	}
+}
+
+pub fn name_validate(
+	schema: &SchemaBuilder,
+	kind: &'static str,
+	name: &str,
+	span: Option<&Span>,
+) -> Result<(), AnyError> {
+	if name.trim().is_empty() {
+		return Err(config_error(span, format!("{} name is empty", kind)));
+	}
+
+	if !schema.name_claim(kind, name) {
+		let message = format!("{} {:?} is declared more than once", kind, name);
+		return Err(config_error(span, message));
+	}
+
+	Ok(())
+}
+
+/// Object of the schema referenced by id from another one
+#[derive(Debug, Clone, Copy)]
+pub enum Reference {
+	FileGroup(FileGroup),
+	Transform(Transform),
+	Collection(Collection),
+	Service(Service),
+	Binary(Binary),
+}
+
+impl From<Dependency> for Reference {
+	fn from(dep: Dependency) -> Self {
+		match dep {
+			Dependency::FileGroup(g) => Reference::FileGroup(g),
+			Dependency::Transform(t) => Reference::Transform(t),
+			Dependency::Toolchain(b) => Reference::Binary(b),
+		}
+	}
+}
+
+impl From<ServiceInput> for Reference {
+	fn from(input: ServiceInput) -> Self {
+		match input {
+			ServiceInput::FileGroup(g) => Reference::FileGroup(g),
+			ServiceInput::Transform(t) => Reference::Transform(t),
+			ServiceInput::Collection(c) => Reference::Collection(c),
+		}
+	}
+}
+
+/// Ids are only valid for objects declared earlier by the same schema
+pub fn reference_validate(
+	schema: &SchemaBuilder,
+	reference: impl Into<Reference>,
+	span: Option<&Span>,
+) -> Result<(), AnyError> {
+	let (kind, id, declared) = match reference.into() {
+		Reference::FileGroup(g) => ("File group", g.as_id(), schema.file_groups.contains(&g)),
+		Reference::Transform(t) => ("Transform", t.as_id(), schema.transforms.contains(&t)),
+		Reference::Collection(c) => ("Collection", c.as_id(), schema.collections.contains(&c)),
+		Reference::Service(s) => ("Service", s.as_id(), schema.services.contains(&s)),
+		Reference::Binary(b) => ("Binary", b.as_id(), schema.binaries.contains(&b)),
+	};
+
+	if !declared {
+		let id = usize::from(id);
+		let message = format!("{} with id {} is not declared in this schema", kind, id);
+		return Err(config_error(span, message));
+	}
+
+	Ok(())
+}
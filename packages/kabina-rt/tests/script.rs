@@ -1 +1,83 @@
+use std::sync::Arc;
+
+use deno_core::url::Url;
+use kabina_db::runtime::Runtime;
+use kabina_db::{file_group_pattern_validate, Database, Schema, SchemaBuilder};
 use kabina_rt::*;
+use parking_lot::Mutex;
+
+async fn load(name: &str, source: &str) -> Result<Schema, anyhow::Error> {
+	let dir = std::env::temp_dir().join(format!("kabina-rt-{}-{}", name, std::process::id()));
+	std::fs::create_dir_all(&dir).unwrap();
+	let module = dir.join("kabina.config.ts");
+	std::fs::write(&module, source).unwrap();
+
+	let db = Arc::new(Mutex::new(Database::new()));
+	let mut runtime = DenoRuntime::new(db).await;
+	let url = Url::from_file_path(&module).unwrap();
+	let schema = runtime.load_schema(url).await;
+
+	let _ = std::fs::remove_dir_all(&dir);
+	schema
+}
+
+#[tokio::test]
+async fn test_load_schema_errors() {
+	let duplicate = r#"
+		import { fileGroup } from "kabina";
+		fileGroup({ name: "src", items: ["*.ts"] });
+		fileGroup({ name: "src", items: ["*.js"] });
+	"#;
+
+	let message = format!("{:#}", load("duplicate", duplicate).await.unwrap_err());
+	assert!(
+		message.contains("File group \"src\" is declared more than once"),
+		"{}",
+		message
+	);
+
+	let glob = r#"
+		import { fileGroup } from "kabina";
+		fileGroup({ name: "src", items: ["src/["] });
+	"#;
+
+	let message = format!("{:#}", load("glob", glob).await.unwrap_err());
+	assert!(
+		message.contains("File group \"src\" has an invalid pattern"),
+		"{}",
+		message
+	);
+
+	let empty = r#"
+		import { fileGroup } from "kabina";
+		fileGroup({ name: " ", items: ["*.ts"] });
+	"#;
+
+	let message = format!("{:#}", load("empty", empty).await.unwrap_err());
+	assert!(message.contains("File group name is empty"), "{}", message);
+
+	let reference = r#"
+		import { server } from "kabina";
+		server({ name: "web", routes: { "/": { kind: "Collection", id: 4096 } } });
+	"#;
+
+	let message = format!("{:#}", load("reference", reference).await.unwrap_err());
+	assert!(
+		message.contains("Collection with id 4096 is not declared in this schema"),
+		"{}",
+		message
+	);
+}
+
+#[test]
+fn test_schema_validation() {
+	assert!(file_group_pattern_validate("src/**/*.rs").is_ok());
+	assert!(file_group_pattern_validate("!**/target").is_ok());
+	assert!(file_group_pattern_validate("src/[a-").is_err());
+	assert!(file_group_pattern_validate("!{a,b").is_err());
+
+	let builder = SchemaBuilder::default();
+	assert!(builder.name_claim("File group", "sources"));
+	assert!(builder.name_claim("Transform", "sources"));
+	assert!(!builder.name_claim("File group", "sources"));
+}
@@ -12,8 +12,19 @@ export type BinaryRuntime = BinaryNative;
 export interface BinaryNative {
   kind: "native";
   executable: string;
+  /**
+   * Values may reference variables with `${NAME}` or `${NAME:-default}` and
+   * ports of other services with `${service.port}` or `${service.ports.name}`
+   */
   env?: { [key: string]: string };
+  /** Interpolated like `env`, variables of `env` are visible too */
   arguments?: string[];
+  /** Relative to the module declaring the binary, the schema directory by default */
+  cwd?: string;
+  /** Files with `KEY=value` lines relative to the module, loaded before `env` */
+  envFile?: string | string[];
+  /** Whether the process sees the environment of the daemon, true by default */
+  inheritEnv?: boolean;
 }
 
 export interface BinaryRunner {
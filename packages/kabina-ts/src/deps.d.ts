@@ -1,4 +1,4 @@
-import { FileGroup, FileMetadata } from "./file.d.ts";
+import { FileContent, FileGroup } from "./file.d.ts";
 import { Job } from "./job.d.ts";
 import { ToolchainRunner } from "./toolchain.d.ts";
 import { Toolchain } from "./toolchain.d.ts";
@@ -15,7 +15,7 @@ export type MapDependenciesToArguments<D> =
 
 
 export type MapDependencyToArgument<D> =
-  D extends FileGroup ? FileMetadata :
+  D extends FileGroup ? FileContent :
   D extends Job<infer O> ? MapDependencyToArgument<O> :
   D extends Toolchain ? ToolchainRunner :
   never;
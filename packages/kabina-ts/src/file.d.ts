@@ -7,7 +7,12 @@ export interface FilePattern {
 export interface FileGroupConfig {
   name: string,
   root?: string,
-  items: (string | FilePattern)[]
+  /** Patterns starting with `!` exclude matching paths */
+  items: (string | FilePattern)[],
+  /** Paths to skip, excluding a directory skips everything inside of it */
+  exclude?: string[],
+  /** Honour `.gitignore` and `.ignore` files, enabled by default */
+  gitignore?: boolean
 }
 
 export interface FileGroup {
@@ -18,11 +23,14 @@ export interface FileGroup {
 export function fileGroup(req: FileGroupConfig): FileGroup
 
 export interface FileMetadata {
-  fileName: string
+  fileName: string,
+  filePath: string,
+  /** `null` when the file is not valid UTF-8 text */
+  content: string | null
 }
 
 export interface FileContent extends FileMetadata {
-  buffer: ArrayBuffer
+  buffer: Uint8Array
 }
 
 export interface File {
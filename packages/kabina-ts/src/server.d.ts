@@ -1,7 +1,19 @@
+import { Collection } from "./collection.d.ts";
 
 export interface ServerConfig {
   name: string
-  routes: { [key: string]: RouteConfig }
+  /** Port to listen on, 8080 by default, `auto` picks a free one */
+  port?: number | 'auto'
+  /**
+   * Maps request paths to collections. A pattern is either a mount point
+   * like `/docs` or a prefix ending with `*`, like `/assets/*` or `*`.
+   */
+  routes: { [pattern: string]: RouteConfig }
+  /**
+   * Reload connected browsers when served files change, enabled by default.
+   * Stylesheets are swapped in place, other changes reload the page.
+   */
+  liveReload?: boolean
 }
 
 export interface Server {
@@ -10,7 +22,4 @@ export interface Server {
 
 export function server(config: ServerConfig): Server;
 
-
-export interface RouteConfig {
-
-}
+export type RouteConfig = Collection;
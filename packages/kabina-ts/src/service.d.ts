@@ -1,8 +1,62 @@
 import { Binary } from "./binary.d.ts"
+import { Collection } from "./collection.d.ts"
+import { FileGroup } from "./file.d.ts"
+import { Transform } from "./transform.d.ts"
+import { ExternalProcessConfig } from "./index.d.ts"
+
+/**
+ * What to do when the service exits, `never` by default. Restarts after
+ * repeated exits are delayed with an exponential backoff.
+ */
+export type RestartPolicy = 'never' | 'on-failure' | 'always'
+
+export type ReadinessProbe =
+  /** The port accepts connections */
+  | { kind: 'tcp', port: number, host?: string }
+  /** GET request to the URL returns a 2xx status */
+  | { kind: 'http', url: string }
+  /** The service writes a line matching the regular expression */
+  | { kind: 'log', pattern: string }
+  /** The command exits successfully */
+  | { kind: 'exec', command: string, arguments?: string[] }
+
+export type ReadinessConfig = ReadinessProbe & {
+  /** Delay between probes in milliseconds, 1000 by default */
+  interval?: number
+  /** Failed probes in a row after which the service is unhealthy, also while it is starting,
+   * 3 by default */
+  failureThreshold?: number
+}
 
 export interface ServiceConfig {
   name: string
   binary: Binary
+  restart?: RestartPolicy
+  /** Also write the output to a rotating file in the kabina state directory */
+  logFile?: boolean
+  /** Called with every line the service writes to stdout */
+  processLogs?: ExternalProcessConfig["processLogs"]
+  /**
+   * Decides when the service is ready. Unhealthy services are restarted
+   * according to the restart policy.
+   */
+  readiness?: ReadinessConfig
+  /** Services started and ready before this one, stopped after it */
+  dependsOn?: Service[]
+  /** The service is rebuilt and restarted when any of the input files change */
+  // deno-lint-ignore no-explicit-any
+  inputs?: (FileGroup | Transform<any> | Collection)[]
+  /** Run before the service starts and after its inputs change */
+  build?: Binary
+  /** Milliseconds to wait after SIGTERM before the process is killed, 5000 by default */
+  stopTimeout?: number
+  /**
+   * Ports the service listens on by name, `auto` ports are allocated by the daemon.
+   * The service gets them as `PORT` and `KABINA_PORT_<NAME>` variables, binaries of
+   * other services refer to them as `${name.port}` for the `http` or the only port,
+   * or `${name.ports.key}`.
+   */
+  ports?: { [name: string]: number | 'auto' }
 }
 
 export interface Service {
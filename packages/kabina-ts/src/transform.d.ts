@@ -10,6 +10,7 @@ export interface TransformBinaryRunner<I, D, O> {
 export interface TransformConfig<I, D, O> {
   name: string,
   input: I,
+  /** Must be pure, it may run in another instance of the module evaluated from scratch */
   run: TransformRuner<I, D, O>
   dependencies?: D
 }
@@ -19,7 +20,9 @@ export interface Transform<O> {
   id: number
 }
 
-export type TransformRuner<I, D, O> = (input: MapDependenciesToArguments<I>, dependencies: MapDependenciesToArguments<D>) => O;
+export type TransformRuner<I, D, O> = (input: MapDependenciesToArguments<I>, dependencies: MapDependenciesToArguments<D>) => O | Promise<O>;
+
+export type TransformOutput = string | { fileName?: string, content: string };
 
 
 export function transform<I extends ArrayLike<Dependency>, D extends MapLike<Dependency>, O>(transform: TransformConfig<I, D, O>): Transform<O>;
\ No newline at end of file